@@ -0,0 +1,72 @@
+//! Proposes a draft chart for a backing track by onset detection and pitch tracking.
+//!
+//! Run with e.g. `cargo run --example auto_chart -- songs/twinkle-twinkle.ogg`, where the path is relative
+//! to the asset directory. The draft is written to `<name>.draft.song` next to the audio unless `--output` is given.
+
+use std::sync::Arc;
+
+use bevy::audio::{AudioSource, Decodable, Source};
+use clap::Parser;
+use mir_project::{
+    editor::ASSET_DIR, game::SCORE_THRESHOLD, mic::{spectra, AnalysisConfig}, songs::SongData, transcribe::{assign_fingering, estimate_tempo, transcribe, FeatureExtractor}
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Draft a chart from a backing track", long_about = None)]
+struct Opt {
+    /// Backing track, relative to the asset directory
+    backing: String,
+
+    /// Chart to write, relative to the asset directory
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Grid subdivisions per beat to quantize to
+    #[arg(short, long, default_value_t = 2)]
+    grid: u32,
+
+    /// Use this tempo instead of estimating one
+    #[arg(short, long)]
+    bpm: Option<f32>,
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let opt = Opt::parse();
+
+    let bytes = std::fs::read(format!("{}/{}", ASSET_DIR, opt.backing))?;
+    let decoder = AudioSource { bytes: Arc::from(bytes) }.decoder();
+    let channels = decoder.channels() as usize;
+    let srate = decoder.sample_rate() as f32;
+
+    let interleaved = decoder.map(|s| s as f32 / i16::MAX as f32).collect::<Vec<_>>();
+    let samples = interleaved.chunks(channels).map(|frame| frame.iter().sum::<f32>() / channels as f32).collect::<Vec<_>>();
+    println!("Decoded {:.1}s of audio at {} Hz", samples.len() as f32 / srate, srate);
+
+    let mut extractor = FeatureExtractor::default();
+    let config = AnalysisConfig::default();
+    let features = spectra(&samples, srate, config).map(|s| extractor.process(&s)).collect::<Vec<_>>();
+
+    let (estimated_bpm, first_beat) = estimate_tempo(&features, srate / config.hop_size as f32);
+    let bpm = opt.bpm.unwrap_or(estimated_bpm.round());
+    println!("Estimated {:.1} BPM, first beat at {:.3}s", estimated_bpm, first_beat);
+
+    let offset = first_beat % (60.0 / bpm);
+    let notes = assign_fingering(&transcribe(&features, bpm, offset, opt.grid, SCORE_THRESHOLD));
+    println!("Transcribed {} notes", notes.len());
+
+    let output = opt.output.unwrap_or_else(|| {
+        let stem = opt.backing.rsplit_once('.').map_or(opt.backing.as_str(), |(stem, _)| stem);
+        format!("{}.draft.song", stem)
+    });
+
+    let song = SongData {
+        backing: Some(opt.backing),
+        bpm,
+        beats_per_bar: 4,
+        notes,
+    };
+    std::fs::write(format!("{}/{}", ASSET_DIR, output), song.to_ron()?)?;
+    println!("Wrote {}/{}", ASSET_DIR, output);
+
+    Ok(())
+}
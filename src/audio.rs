@@ -0,0 +1,196 @@
+use std::{sync::Arc, time::Duration};
+
+use bevy::{audio::{AddAudioSource, Decodable, Source, Volume}, prelude::*};
+
+pub const CLICK_DURATION: Duration = Duration::from_millis(40);
+pub const CLICK_PITCH: f32 = 880.0;
+pub const ACCENT_CLICK_PITCH: f32 = 1760.0;
+
+pub const PLUCK_SAMPLE_RATE: u32 = 44100;
+pub const PLUCK_DECAY: f32 = 0.996;
+
+pub struct AudioSourcesPlugin;
+
+impl Plugin for AudioSourcesPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_audio_source::<SkippedAudio>()
+            .add_audio_source::<Pluck>()
+            .add_audio_source::<Recording>()
+            .init_resource::<MetronomeClicks>();
+    }
+}
+
+/// An [`AudioSource`] that starts playing partway through, since Bevy's sinks can't seek.
+#[derive(Asset, TypePath)]
+pub struct SkippedAudio {
+    pub source: AudioSource,
+    pub skip: Duration,
+}
+
+impl Decodable for SkippedAudio {
+    type DecoderItem = i16;
+    type Decoder = Box<dyn Source<Item = i16> + Send>;
+
+    fn decoder(&self) -> Self::Decoder {
+        Box::new(self.source.decoder().skip_duration(self.skip))
+    }
+}
+
+/// A plucked string, synthesized with Karplus-Strong.
+#[derive(Asset, TypePath, Clone, Copy)]
+pub struct Pluck {
+    pub frequency: f32,
+    pub duration: Duration,
+}
+
+impl Pluck {
+    pub fn new(frequency: f32, duration: Duration) -> Self {
+        Pluck { frequency, duration }
+    }
+}
+
+pub struct PluckDecoder {
+    delay_line: Vec<f32>,
+    index: usize,
+    remaining: usize,
+}
+
+impl Decodable for Pluck {
+    type DecoderItem = f32;
+    type Decoder = PluckDecoder;
+
+    fn decoder(&self) -> Self::Decoder {
+        // The averaging filter delays by half a sample, which the delay line makes up for
+        let length = (PLUCK_SAMPLE_RATE as f32 / self.frequency - 0.5).round().max(2.0) as usize;
+
+        // A cheap xorshift is plenty for the initial burst of noise
+        let mut state = self.frequency.to_bits() | 1;
+        let delay_line = (0..length).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as f32 / u32::MAX as f32 - 0.5
+        }).collect();
+
+        PluckDecoder {
+            delay_line,
+            index: 0,
+            remaining: (self.duration.as_secs_f32() * PLUCK_SAMPLE_RATE as f32) as usize,
+        }
+    }
+}
+
+impl Iterator for PluckDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let next = (self.index + 1) % self.delay_line.len();
+        let sample = self.delay_line[self.index];
+        self.delay_line[self.index] = PLUCK_DECAY * 0.5 * (sample + self.delay_line[next]);
+        self.index = next;
+
+        Some(sample)
+    }
+}
+
+impl Source for PluckDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        PLUCK_SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Recorded input, played from `start`, an index into the interleaved samples.
+#[derive(Asset, TypePath, Clone)]
+pub struct Recording {
+    pub samples: Arc<Vec<f32>>,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub start: usize,
+}
+
+pub struct RecordingDecoder {
+    recording: Recording,
+    index: usize,
+}
+
+impl Decodable for Recording {
+    type DecoderItem = f32;
+    type Decoder = RecordingDecoder;
+
+    fn decoder(&self) -> Self::Decoder {
+        // Starting partway through a frame would swap the channels around
+        let start = self.start - self.start % self.channels.max(1) as usize;
+        RecordingDecoder { recording: self.clone(), index: start }
+    }
+}
+
+impl Iterator for RecordingDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.recording.samples.get(self.index).copied();
+        self.index += 1;
+        sample
+    }
+}
+
+impl Source for RecordingDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.recording.samples.len().saturating_sub(self.index))
+    }
+
+    fn channels(&self) -> u16 {
+        self.recording.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.recording.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[derive(Resource)]
+pub struct MetronomeClicks {
+    pub beat: Handle<Pitch>,
+    pub accent: Handle<Pitch>,
+}
+
+impl FromWorld for MetronomeClicks {
+    fn from_world(world: &mut World) -> Self {
+        let mut pitches = world.resource_mut::<Assets<Pitch>>();
+        MetronomeClicks {
+            beat: pitches.add(Pitch::new(CLICK_PITCH, CLICK_DURATION)),
+            accent: pitches.add(Pitch::new(ACCENT_CLICK_PITCH, CLICK_DURATION)),
+        }
+    }
+}
+
+impl MetronomeClicks {
+    pub fn spawn(&self, commands: &mut Commands, accent: bool, volume: f32) {
+        let source = if accent { self.accent.clone() } else { self.beat.clone() };
+        commands.spawn(PitchBundle {
+            source,
+            settings: PlaybackSettings::DESPAWN.with_volume(Volume::new(volume)),
+        });
+    }
+}
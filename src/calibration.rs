@@ -0,0 +1,255 @@
+use std::{collections::HashMap, path::Path};
+
+use bevy::{prelude::*, utils::thiserror::Error};
+use bevy_egui::{egui::{self, Color32}, EguiContexts};
+use cpal::traits::DeviceTrait;
+use serde::{Deserialize, Serialize};
+
+use crate::{game::{calculate_score, SCORE_THRESHOLD}, mic::MagnitudeSpectrum, settings::AvailableDevices, songs::Tab, tuner::Tuning, GameState};
+
+pub const PROFILES_PATH: &str = "device_profiles.ron";
+
+/// Where an average strum lands once normalized, in dB above the noise floor.
+pub const REFERENCE_STRUM_DB: f32 = 30.0;
+/// How far below a string's calibrated strum its threshold sits, so softer playing still counts.
+pub const CALIBRATION_HEADROOM_DB: f32 = 15.0;
+/// Lowest a threshold may sit before normalization, so a quiet device isn't triggered by its own noise.
+pub const MIN_STRING_THRESHOLD: f32 = 6.0;
+/// A strum has to reach this before calibration takes it as one.
+pub const MIN_STRUM_DB: f32 = 10.0;
+/// Seconds to keep listening after a strum starts, to catch its peak.
+pub const STRUM_LISTEN_TIME: f32 = 1.0;
+
+pub struct CalibrationPlugin;
+
+impl Plugin for CalibrationPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<DeviceProfiles>()
+            .init_resource::<InputProfile>()
+            .init_resource::<Calibrator>()
+            .add_systems(Update, load_device_profile.run_if(resource_changed::<AvailableDevices>))
+            .add_systems(Update, (update_calibration, calibration_window).chain().run_if(in_state(GameState::Settings)));
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    RonSpannedError(#[from] ron::error::SpannedError),
+
+    #[error(transparent)]
+    RonError(#[from] ron::Error),
+}
+
+/// How loud one input device plays, learnt by calibration.
+#[derive(Resource, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct InputProfile {
+    /// Added to every score, so an average strum on this device scores [`REFERENCE_STRUM_DB`].
+    pub gain_db: f32,
+    /// Normalized score each string has to reach to count as hit.
+    pub thresholds: HashMap<Tab, f32>,
+    /// Overrides for fret ranges of a string, where its tone changes up the neck.
+    #[serde(default)]
+    pub fret_thresholds: Vec<FretThreshold>,
+}
+
+/// A threshold for the inclusive range of frets `from..=to` on one string.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FretThreshold {
+    pub tab: Tab,
+    pub from: u32,
+    pub to: u32,
+    pub threshold: f32,
+}
+
+impl InputProfile {
+    pub fn string_threshold(&self, tab: Tab) -> f32 {
+        self.thresholds.get(&tab).copied().unwrap_or(SCORE_THRESHOLD)
+    }
+
+    /// The threshold for a note, from the first fret range covering it, or else its string.
+    pub fn threshold(&self, tab: Tab, fret: u32) -> f32 {
+        self.fret_thresholds.iter()
+            .find(|range| range.tab == tab && range.from <= fret && fret <= range.to)
+            .map_or_else(|| self.string_threshold(tab), |range| range.threshold)
+    }
+
+    /// Derives a profile from the peak score of a strum on each string.
+    pub fn from_peaks(peaks: &[(Tab, f32)]) -> Self {
+        let mean = peaks.iter().map(|(_, peak)| peak).sum::<f32>() / peaks.len().max(1) as f32;
+        let gain_db = REFERENCE_STRUM_DB - mean;
+
+        InputProfile {
+            gain_db,
+            thresholds: peaks.iter()
+                .map(|(tab, peak)| (*tab, (peak - CALIBRATION_HEADROOM_DB).max(MIN_STRING_THRESHOLD) + gain_db))
+                .collect(),
+            fret_thresholds: Vec::new(),
+        }
+    }
+}
+
+/// Calibrated profiles by device name, saved to [`PROFILES_PATH`].
+#[derive(Resource, Default, Serialize, Deserialize)]
+pub struct DeviceProfiles {
+    pub profiles: HashMap<String, InputProfile>,
+}
+
+impl DeviceProfiles {
+    pub fn load() -> Result<Self, ProfileError> {
+        Self::load_from(PROFILES_PATH)
+    }
+
+    pub fn save(&self) -> Result<(), ProfileError> {
+        self.save_to(PROFILES_PATH)
+    }
+
+    pub fn load_from(path: impl AsRef<Path>) -> Result<Self, ProfileError> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(ron::de::from_str(&text)?)
+    }
+
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), ProfileError> {
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+}
+
+/// Walks the player through strumming each open string.
+#[derive(Resource, Default)]
+pub struct Calibrator {
+    pub open: bool,
+    string: usize,
+    peak: f32,
+    strum_started: Option<f32>,
+    peaks: Vec<(Tab, f32)>,
+    status: String,
+}
+
+impl Calibrator {
+    pub fn start(&mut self) {
+        *self = Calibrator { open: true, ..default() };
+    }
+
+    fn current(&self) -> Option<Tab> {
+        Tab::ALL.get(self.string).copied()
+    }
+}
+
+/// Switches to a device's saved profile when it connects. Nothing else about the devices changing reloads it,
+/// so unsaved edits to the live profile are kept.
+fn load_device_profile(
+    devices: Res<AvailableDevices>,
+    mut profiles: ResMut<DeviceProfiles>,
+    mut profile: ResMut<InputProfile>,
+    mut loaded: Local<bool>,
+    mut loaded_for: Local<Option<String>>,
+) {
+    if !*loaded {
+        *loaded = true;
+        if let Ok(saved) = DeviceProfiles::load() {
+            *profiles = saved;
+        }
+    }
+
+    let name = devices.connected.as_ref().and_then(|dev| dev.name().ok());
+    if *loaded_for == name {
+        return;
+    }
+    loaded_for.clone_from(&name);
+
+    let found = name.and_then(|name| profiles.profiles.get(&name).cloned()).unwrap_or_default();
+    if *profile != found {
+        *profile = found;
+    }
+}
+
+fn update_calibration(
+    mut calibrator: ResMut<Calibrator>,
+    mut spectra: EventReader<MagnitudeSpectrum>,
+    tuning: Res<Tuning>,
+) {
+    let Some(tab) = calibrator.current().filter(|_| calibrator.open) else {
+        spectra.clear();
+        return;
+    };
+
+    for spectrum in spectra.read() {
+        let score = calculate_score(tuning.adjust(tab.pitch()), spectrum, &InputProfile::default());
+        let time = spectrum.progress.as_secs_f32();
+
+        if calibrator.strum_started.is_none() && score > MIN_STRUM_DB {
+            calibrator.strum_started = Some(time);
+        }
+        let Some(started) = calibrator.strum_started else { continue };
+
+        calibrator.peak = calibrator.peak.max(score);
+        if time - started > STRUM_LISTEN_TIME {
+            let peak = calibrator.peak;
+            calibrator.peaks.push((tab, peak));
+            calibrator.string += 1;
+            calibrator.peak = 0.0;
+            calibrator.strum_started = None;
+        }
+    }
+}
+
+fn calibration_window(
+    mut contexts: EguiContexts,
+    mut calibrator: ResMut<Calibrator>,
+    mut profiles: ResMut<DeviceProfiles>,
+    mut profile: ResMut<InputProfile>,
+    devices: Res<AvailableDevices>,
+) {
+    let mut open = calibrator.open;
+
+    egui::Window::new("Calibrate Input").open(&mut open).resizable(false).show(contexts.ctx_mut(), |ui| {
+        let Some(name) = devices.connected.as_ref().and_then(|dev| dev.name().ok()) else {
+            ui.label("Connect a device first");
+            return;
+        };
+
+        for (tab, peak) in calibrator.peaks.iter() {
+            ui.label(format!("{:?} string: {:.0} dB", tab, peak));
+        }
+
+        match calibrator.current() {
+            Some(tab) => {
+                let heard = if calibrator.strum_started.is_some() { "Listening..." } else { "" };
+                ui.heading(format!("Strum the open {:?} string", tab));
+                ui.label(heard);
+            },
+            None => {
+                let calibrated = InputProfile::from_peaks(&calibrator.peaks);
+                ui.label(format!("Normalization: {:+.1} dB", calibrated.gain_db));
+                for tab in Tab::ALL {
+                    ui.label(format!("{:?} threshold: {:.1} dB", tab, calibrated.string_threshold(tab)));
+                }
+
+                if ui.button("Save").clicked() {
+                    profiles.profiles.insert(name, calibrated.clone());
+                    *profile = calibrated;
+                    calibrator.status = match profiles.save() {
+                        Ok(()) => format!("Saved to {}", PROFILES_PATH),
+                        Err(e) => format!("Failed to save: {}", e),
+                    };
+                }
+            },
+        }
+
+        if ui.button("Restart").clicked() {
+            calibrator.start();
+        }
+
+        if !calibrator.status.is_empty() {
+            ui.colored_label(Color32::LIGHT_BLUE, &calibrator.status);
+        }
+    });
+
+    calibrator.open = open;
+}
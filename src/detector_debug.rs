@@ -0,0 +1,123 @@
+use bevy::prelude::*;
+use bevy_egui::{egui::{self, Color32}, EguiContexts};
+use egui_plot::{HLine, Line, Plot, PlotPoints, VLine};
+
+use crate::{
+    calibration::InputProfile,
+    game::{Confusion, CurrentSong, Miss, NoteHitData, NoteResult, HIT_FORGIVENESS, TIMING_WINDOW},
+    songs::{midi_to_name, Note},
+    GameState,
+};
+
+pub const DEBUG_KEY: KeyCode = KeyCode::F3;
+/// Most notes still being listened for that get a score plot, soonest first.
+pub const MAX_DEBUG_NOTES: usize = 4;
+/// How many of the latest judgements are listed under the plots.
+pub const DEBUG_RESULTS_SHOWN: usize = 6;
+pub const DEBUG_PLOT_HEIGHT: f32 = 90.0;
+
+pub struct DetectorDebugPlugin;
+
+impl Plugin for DetectorDebugPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<DetectorDebug>()
+            .add_systems(Update, (toggle_debug, debug_overlay).chain().run_if(in_state(GameState::SongPlaying).or_else(in_state(GameState::Paused))));
+    }
+}
+
+/// Whether the detector's workings are shown over the highway.
+#[derive(Resource, Default)]
+pub struct DetectorDebug {
+    pub enabled: bool,
+}
+
+fn toggle_debug(keys: Res<ButtonInput<KeyCode>>, mut debug: ResMut<DetectorDebug>) {
+    if keys.just_pressed(DEBUG_KEY) {
+        debug.enabled = !debug.enabled;
+    }
+}
+
+/// Says how a note was judged and what decided it.
+fn judgement(result: &NoteResult) -> (String, Color32) {
+    let name = midi_to_name(result.note.midi());
+    let best = result.hit_data.best_score().map_or_else(|| "nothing".to_owned(), |best| format!("{:.1}", best));
+    // The first onset outside the window is what made it early or late
+    let outside = |early: bool| result.hit_data.onsets(result.threshold)
+        .find(|diff| if early { *diff < -HIT_FORGIVENESS } else { *diff > HIT_FORGIVENESS })
+        .map_or(0.0, |diff| diff * 1000.0);
+
+    match result.miss {
+        None => {
+            let (diff, score) = result.hit_data.hit_frame(result.threshold).unwrap_or_default();
+            (format!("{} hit at {:+.0} ms, {:.1} over {:.1}", name, diff * 1000.0, score, result.threshold), Color32::LIGHT_GREEN)
+        },
+        Some(Miss::Early) => (format!("{} early by {:.0} ms", name, -outside(true)), Color32::from_rgb(255, 165, 0)),
+        Some(Miss::Late) => (format!("{} late by {:.0} ms", name, outside(false)), Color32::from_rgb(255, 165, 0)),
+        Some(Miss::WrongPitch(played)) => (format!("{} missed, {} played instead", name, midi_to_name(played)), Color32::LIGHT_RED),
+        Some(Miss::Silent) => {
+            let confusion = match result.hit_data.confusion {
+                Some(Confusion::WrongOctave) => ", penalized as the wrong octave",
+                Some(Confusion::SharedHarmonic) => ", penalized as a shared harmonic",
+                None => "",
+            };
+            (format!("{} missed, best {} under {:.1}{}", name, best, result.threshold, confusion), Color32::LIGHT_RED)
+        },
+    }
+}
+
+/// The note's score against its offset from when it was due, with the hit window and threshold it has to clear.
+fn score_plot(ui: &mut egui::Ui, id: Entity, hit_data: &NoteHitData, threshold: f32) {
+    let points: PlotPoints = hit_data.data.iter().map(|(diff, score)| [*diff as f64, *score as f64]).collect();
+    let edge = (HIT_FORGIVENESS + TIMING_WINDOW) as f64;
+
+    Plot::new(id)
+        .height(DEBUG_PLOT_HEIGHT)
+        .allow_drag(false)
+        .allow_zoom(false)
+        .allow_scroll(false)
+        .show_axes([false, true])
+        .include_x(-edge)
+        .include_x(edge)
+        .include_y(0.0)
+        .include_y(threshold as f64 * 1.5)
+        .show(ui, |plot_ui| {
+            plot_ui.vline(VLine::new(-HIT_FORGIVENESS as f64).color(Color32::DARK_GRAY));
+            plot_ui.vline(VLine::new(HIT_FORGIVENESS as f64).color(Color32::DARK_GRAY));
+            plot_ui.hline(HLine::new(threshold as f64).color(Color32::RED).name("Threshold"));
+            plot_ui.line(Line::new(points).color(Color32::LIGHT_BLUE).name("Score"));
+        });
+}
+
+fn debug_overlay(
+    mut contexts: EguiContexts,
+    debug: Res<DetectorDebug>,
+    notes: Query<(Entity, &Note, &NoteHitData)>,
+    song_data: Res<CurrentSong>,
+    profile: Res<InputProfile>,
+) {
+    if !debug.enabled {
+        return;
+    }
+
+    let mut active: Vec<_> = notes.iter().filter(|(_, _, hit_data)| !hit_data.data.is_empty()).collect();
+    active.sort_by(|(_, a, _), (_, b, _)| a.beat.total_cmp(&b.beat));
+
+    egui::Window::new("Detector")
+        .anchor(egui::Align2::LEFT_TOP, egui::vec2(10.0, 10.0))
+        .default_width(320.0)
+        .show(contexts.ctx_mut(), |ui| {
+            for (entity, note, hit_data) in active.into_iter().take(MAX_DEBUG_NOTES) {
+                let threshold = profile.threshold(note.tab, note.fret);
+                let best = hit_data.best_score().map_or_else(|| "-".to_owned(), |best| format!("{:.1}", best));
+                let state = if hit_data.window_closed { ", listening for late" } else { "" };
+                ui.label(format!("{} ({:?} fret {}): best {} of {:.1}{}", midi_to_name(note.midi()), note.tab, note.fret, best, threshold, state));
+                score_plot(ui, entity, hit_data, threshold);
+            }
+
+            ui.separator();
+            for result in song_data.results().iter().rev().take(DEBUG_RESULTS_SHOWN) {
+                let (text, colour) = judgement(result);
+                ui.colored_label(colour, text);
+            }
+        });
+}
@@ -0,0 +1,502 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, utils::thiserror::Error};
+use bevy_egui::{egui::{self, Align2, Color32, FontId, Key, Modifiers, Pos2, Sense, Stroke}, EguiContexts};
+
+use crate::{audio::{Pluck, SkippedAudio}, game::{despawn_all, ReferenceGuide}, songs::{Note, SongData, Tab}, GameState};
+
+pub const ASSET_DIR: &str = "assets";
+
+pub const MAX_FRET: u32 = 24;
+pub const GRID_DIVISIONS: [u32; 6] = [1, 2, 3, 4, 6, 8];
+
+pub const LANE_SPACE: f32 = 48.0;
+pub const PIXELS_PER_BEAT: f32 = 80.0;
+pub const PLAYHEAD_X: f32 = 0.25;
+pub const EDITOR_NOTE_RADIUS: f32 = 14.0;
+
+pub struct EditorPlugin;
+
+impl Plugin for EditorPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_systems(OnExit(GameState::Editor), despawn_all::<EditorBacking>)
+            .add_systems(Update, (editor_ui, editor_playback).chain().run_if(in_state(GameState::Editor)));
+    }
+}
+
+#[derive(Component)]
+pub struct EditorBacking;
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum ChartEditorError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    RonSpannedError(#[from] ron::error::SpannedError),
+
+    #[error(transparent)]
+    RonError(#[from] ron::Error),
+
+    #[error("\"{0}\" isn't a valid chart name")]
+    InvalidName(String),
+
+    #[error("{0} already exists")]
+    AlreadyExists(String),
+}
+
+#[derive(Resource)]
+pub struct ChartEditor {
+    path: String,
+    chart: SongData,
+    backing: Option<Handle<AudioSource>>,
+    playhead: f32,
+    playing: bool,
+    restart_playback: bool,
+    preview_speed: f32,
+    grid: u32,
+    lane: Tab,
+    fret: u32,
+    typing_fret: bool,
+    undo: Vec<Vec<Note>>,
+    redo: Vec<Vec<Note>>,
+    clipboard: Vec<Note>,
+    status: String,
+}
+
+impl ChartEditor {
+    /// Opens the chart at `path`, relative to the asset directory.
+    pub fn open(path: &str, asset_server: &AssetServer) -> Result<Self, ChartEditorError> {
+        let text = std::fs::read_to_string(format!("{}/{}", ASSET_DIR, path))?;
+        let chart = ron::de::from_str::<SongData>(&text)?;
+        let backing = chart.backing.as_ref().map(|s| asset_server.load(s));
+
+        Ok(ChartEditor {
+            path: path.to_owned(),
+            chart,
+            backing,
+            playhead: 0.0,
+            playing: false,
+            restart_playback: false,
+            preview_speed: 1.0,
+            grid: 2,
+            lane: Tab::E2,
+            fret: 0,
+            typing_fret: false,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            clipboard: Vec::new(),
+            status: String::new(),
+        })
+    }
+
+    pub fn save(&self) -> Result<(), ChartEditorError> {
+        let text = self.chart.to_ron()?;
+        std::fs::write(format!("{}/{}", ASSET_DIR, self.path), text)?;
+        Ok(())
+    }
+
+    fn bps(&self) -> f32 {
+        self.chart.bpm / 60.0
+    }
+
+    fn snap(&self, beat: f32) -> f32 {
+        let step = 1.0 / self.grid as f32;
+        ((beat / step).round() * step).max(0.0)
+    }
+
+    fn beats_per_measure(&self) -> f32 {
+        self.chart.beats_per_bar as f32
+    }
+
+    fn measure_start(&self) -> f32 {
+        (self.playhead / self.beats_per_measure()).floor() * self.beats_per_measure()
+    }
+
+    fn seek(&mut self, beat: f32) {
+        self.playhead = beat.max(0.0);
+        self.restart_playback = true;
+    }
+
+    /// Applies `f` to the notes as a single undoable step.
+    fn edit(&mut self, f: impl FnOnce(&mut Vec<Note>)) {
+        self.undo.push(self.chart.notes.clone());
+        self.redo.clear();
+        f(&mut self.chart.notes);
+        self.chart.notes.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+    }
+
+    fn undo(&mut self) {
+        if let Some(notes) = self.undo.pop() {
+            self.redo.push(std::mem::replace(&mut self.chart.notes, notes));
+        }
+    }
+
+    fn redo(&mut self) {
+        if let Some(notes) = self.redo.pop() {
+            self.undo.push(std::mem::replace(&mut self.chart.notes, notes));
+        }
+    }
+
+    fn note_at(&self, tab: Tab, beat: f32) -> Option<usize> {
+        self.chart.notes.iter().position(|n| n.tab == tab && (n.beat - beat).abs() < 1e-3)
+    }
+
+    fn place(&mut self, tab: Tab, beat: f32) {
+        let note = Note { tab, fret: self.fret, beat };
+        if self.note_at(tab, beat).is_some_and(|i| self.chart.notes[i] == note) {
+            return;
+        }
+        self.edit(|notes| {
+            notes.retain(|n| !(n.tab == tab && (n.beat - beat).abs() < 1e-3));
+            notes.push(note);
+        });
+    }
+
+    fn remove(&mut self, tab: Tab, beat: f32) {
+        if let Some(i) = self.note_at(tab, beat) {
+            self.edit(|notes| { notes.remove(i); });
+        }
+    }
+
+    fn copy_measure(&mut self) {
+        let (start, length) = (self.measure_start(), self.beats_per_measure());
+        self.clipboard = self.chart.notes.iter()
+            .filter(|n| start <= n.beat && n.beat < start + length)
+            .map(|n| Note { beat: n.beat - start, ..n.clone() })
+            .collect();
+        self.status = format!("Copied measure {}", measure_number(start, length));
+    }
+
+    fn paste_measure(&mut self) {
+        let (start, length) = (self.measure_start(), self.beats_per_measure());
+        let pasted = self.clipboard.iter().map(|n| Note { beat: n.beat + start, ..n.clone() }).collect::<Vec<_>>();
+        self.edit(|notes| {
+            notes.retain(|n| !(start <= n.beat && n.beat < start + length));
+            notes.extend(pasted);
+        });
+        self.status = format!("Pasted into measure {}", measure_number(start, length));
+    }
+
+    fn type_digit(&mut self, digit: u32) {
+        let extended = self.fret * 10 + digit;
+        self.fret = if self.typing_fret && extended <= MAX_FRET { extended } else { digit };
+        self.typing_fret = true;
+    }
+}
+
+#[inline]
+fn measure_number(beat: f32, beats_per_measure: f32) -> u32 {
+    (beat / beats_per_measure).floor() as u32 + 1
+}
+
+/// Lanes top to bottom, with the high string on top like written tab.
+#[inline]
+fn tab_to_lane(tab: Tab) -> f32 {
+    match tab {
+        Tab::E4 => 0.0,
+        Tab::B3 => 1.0,
+        Tab::G3 => 2.0,
+        Tab::D3 => 3.0,
+        Tab::A2 => 4.0,
+        Tab::E2 => 5.0,
+    }
+}
+
+#[inline]
+fn lane_to_tab(lane: usize) -> Option<Tab> {
+    Tab::ALL.iter().rev().nth(lane).copied()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn editor_playback(
+    mut commands: Commands,
+    time: Res<Time>,
+    audio: Res<Assets<AudioSource>>,
+    mut skipped_audio: ResMut<Assets<SkippedAudio>>,
+    backing: Query<Entity, With<EditorBacking>>,
+    guide: Res<ReferenceGuide>,
+    mut plucks: ResMut<Assets<Pluck>>,
+    mut editor: ResMut<ChartEditor>,
+) {
+    if !editor.playing {
+        for e in backing.iter() {
+            commands.entity(e).despawn_recursive();
+        }
+        return;
+    }
+
+    if editor.restart_playback {
+        editor.restart_playback = false;
+        for e in backing.iter() {
+            commands.entity(e).despawn_recursive();
+        }
+        if let Some(source) = editor.backing.as_ref().and_then(|h| audio.get(h)) {
+            let skipped = SkippedAudio {
+                source: source.clone(),
+                skip: Duration::from_secs_f32(editor.playhead / editor.bps()),
+            };
+            commands.spawn((
+                AudioSourceBundle {
+                    source: skipped_audio.add(skipped),
+                    settings: PlaybackSettings::DESPAWN.with_speed(editor.preview_speed),
+                },
+                EditorBacking
+            ));
+        }
+    }
+
+    let prev_playhead = editor.playhead;
+    editor.playhead += time.delta_seconds() * editor.bps() * editor.preview_speed;
+
+    if guide.enabled {
+        for note in editor.chart.notes.iter().filter(|n| prev_playhead <= n.beat && n.beat < editor.playhead) {
+            let bar = (note.beat / editor.beats_per_measure()) as u32 + 1;
+            if !guide.is_muted(bar) {
+                guide.play(&mut commands, &mut plucks, note);
+            }
+        }
+    }
+}
+
+fn editor_ui(
+    mut contexts: EguiContexts,
+    mut editor: ResMut<ChartEditor>,
+    mut guide: ResMut<ReferenceGuide>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let ctx = contexts.ctx_mut();
+
+    egui::TopBottomPanel::top("editor_toolbar").show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            ui.heading(format!("Editing {}", editor.path));
+            ui.separator();
+            if ui.button("Save").clicked() {
+                save(&mut editor);
+            }
+            if ui.button("Main Menu").clicked() {
+                next_state.set(GameState::Settings);
+            }
+            ui.label(&editor.status);
+        });
+
+        ui.horizontal(|ui| {
+            let play_label = if editor.playing { "Pause" } else { "Play" };
+            if ui.button(play_label).clicked() {
+                toggle_playback(&mut editor);
+            }
+            if ui.button("Rewind").clicked() {
+                editor.seek(0.0);
+            }
+            ui.add_enabled_ui(!editor.playing, |ui| {
+                ui.add(egui::Slider::new(&mut editor.preview_speed, 0.25..=1.0).text("Preview speed"));
+            });
+            ui.checkbox(&mut guide.enabled, "Guide");
+            ui.separator();
+            ui.add(egui::DragValue::new(&mut editor.chart.bpm).clamp_range(20.0..=300.0).prefix("BPM: "));
+            ui.add(egui::DragValue::new(&mut editor.chart.beats_per_bar).clamp_range(1..=12).prefix("Beats per bar: "));
+            egui::ComboBox::from_label("Grid")
+                .selected_text(format!("1/{}", editor.grid))
+                .show_ui(ui, |ui| {
+                    for division in GRID_DIVISIONS {
+                        ui.selectable_value(&mut editor.grid, division, format!("1/{}", division));
+                    }
+                });
+            ui.add(egui::DragValue::new(&mut editor.fret).clamp_range(0..=MAX_FRET).prefix("Fret: "));
+        });
+
+        ui.horizontal(|ui| {
+            if ui.add_enabled(!editor.undo.is_empty(), egui::Button::new("Undo")).clicked() {
+                editor.undo();
+            }
+            if ui.add_enabled(!editor.redo.is_empty(), egui::Button::new("Redo")).clicked() {
+                editor.redo();
+            }
+            if ui.button("Copy Measure").clicked() {
+                editor.copy_measure();
+            }
+            if ui.add_enabled(!editor.clipboard.is_empty(), egui::Button::new("Paste Measure")).clicked() {
+                editor.paste_measure();
+            }
+            ui.separator();
+            let beats_per_measure = editor.beats_per_measure();
+            ui.label(format!("Measure {}, beat {:.2}", measure_number(editor.playhead, beats_per_measure), editor.playhead % beats_per_measure + 1.0));
+        });
+    });
+
+    egui::TopBottomPanel::bottom("editor_help").show(ctx, |ui| {
+        ui.label("Click: place note   Right click: delete   Drag/scroll: scrub   Arrows: move cursor   0-9: fret   \
+            Enter: place   Del: delete   Space: play   Ctrl+Z/Y: undo/redo   Ctrl+C/V: copy/paste measure   Ctrl+S: save");
+    });
+
+    if !ctx.wants_keyboard_input() {
+        handle_editor_keys(ctx, &mut editor);
+    }
+
+    egui::CentralPanel::default().show(ctx, |ui| {
+        let (response, painter) = ui.allocate_painter(ui.available_size(), Sense::click_and_drag());
+        let rect = response.rect;
+
+        let top = rect.center().y - LANE_SPACE * 2.5;
+        let playhead_x = rect.left() + rect.width() * PLAYHEAD_X;
+        let beat_to_x = |beat: f32| playhead_x + (beat - editor.playhead) * PIXELS_PER_BEAT;
+        let x_to_beat = |x: f32| editor.playhead + (x - playhead_x) / PIXELS_PER_BEAT;
+
+        let first_beat = x_to_beat(rect.left()).floor().max(0.0);
+        let last_beat = x_to_beat(rect.right()).ceil();
+
+        // Grid lines, with measure lines and beat lines drawn over subdivisions. Lines are counted in whole
+        // steps so thirds and sixths of a beat still land exactly on beats and measures
+        let steps_per_beat = editor.grid;
+        let steps_per_measure = editor.chart.beats_per_bar.max(1) * steps_per_beat;
+        for step in first_beat as u32 * steps_per_beat..=last_beat as u32 * steps_per_beat {
+            let beat = step as f32 / steps_per_beat as f32;
+            let x = beat_to_x(beat);
+            let (width, color) = if step % steps_per_measure == 0 {
+                (2.0, Color32::WHITE)
+            } else if step % steps_per_beat == 0 {
+                (1.0, Color32::GRAY)
+            } else {
+                (1.0, Color32::DARK_GRAY)
+            };
+            painter.line_segment([Pos2::new(x, top), Pos2::new(x, top + LANE_SPACE * 5.0)], Stroke::new(width, color));
+            if step % steps_per_measure == 0 {
+                painter.text(Pos2::new(x + 4.0, top - LANE_SPACE * 0.5), Align2::LEFT_BOTTOM, format!("m.{}", step / steps_per_measure + 1), FontId::proportional(14.0), Color32::WHITE);
+            }
+        }
+
+        for tab in Tab::ALL {
+            let y = top + tab_to_lane(tab) * LANE_SPACE;
+            painter.line_segment([Pos2::new(rect.left(), y), Pos2::new(rect.right(), y)], Stroke::new(1.0, Color32::LIGHT_GRAY));
+            painter.text(Pos2::new(rect.left() + 4.0, y), Align2::LEFT_CENTER, format!("{:?}", tab), FontId::proportional(14.0), Color32::LIGHT_GRAY);
+        }
+
+        for note in editor.chart.notes.iter().filter(|n| first_beat - 1.0 <= n.beat && n.beat <= last_beat + 1.0) {
+            let center = Pos2::new(beat_to_x(note.beat), top + tab_to_lane(note.tab) * LANE_SPACE);
+            painter.circle(center, EDITOR_NOTE_RADIUS, Color32::BLACK, Stroke::new(2.0, Color32::GOLD));
+            painter.text(center, Align2::CENTER_CENTER, format!("{}", note.fret), FontId::proportional(16.0), Color32::GOLD);
+        }
+
+        // Keyboard cursor
+        let cursor = Pos2::new(beat_to_x(editor.snap(editor.playhead)), top + tab_to_lane(editor.lane) * LANE_SPACE);
+        painter.circle_stroke(cursor, EDITOR_NOTE_RADIUS + 3.0, Stroke::new(2.0, Color32::LIGHT_BLUE));
+        painter.line_segment([Pos2::new(playhead_x, rect.top()), Pos2::new(playhead_x, rect.bottom())], Stroke::new(1.0, Color32::RED));
+
+        let hovered = response.hover_pos().and_then(|pos| {
+            let lane = ((pos.y - top) / LANE_SPACE).round();
+            if !(0.0..6.0).contains(&lane) {
+                return None;
+            }
+            Some((lane_to_tab(lane as usize)?, editor.snap(x_to_beat(pos.x))))
+        });
+
+        if let Some((tab, beat)) = hovered {
+            let center = Pos2::new(beat_to_x(beat), top + tab_to_lane(tab) * LANE_SPACE);
+            painter.circle_stroke(center, EDITOR_NOTE_RADIUS, Stroke::new(1.0, Color32::from_white_alpha(96)));
+            painter.text(center, Align2::CENTER_CENTER, format!("{}", editor.fret), FontId::proportional(16.0), Color32::from_white_alpha(96));
+
+            if response.clicked() {
+                editor.lane = tab;
+                editor.place(tab, beat);
+            }
+            else if response.secondary_clicked() {
+                editor.remove(tab, beat);
+            }
+        }
+
+        if response.dragged() {
+            let beat = editor.playhead - response.drag_delta().x / PIXELS_PER_BEAT;
+            editor.seek(beat);
+        }
+        if response.hovered() {
+            let scroll = ui.input(|i| i.smooth_scroll_delta);
+            if scroll != egui::Vec2::ZERO {
+                let beat = editor.playhead - (scroll.x + scroll.y) / PIXELS_PER_BEAT;
+                editor.seek(beat);
+            }
+        }
+    });
+}
+
+fn handle_editor_keys(ctx: &egui::Context, editor: &mut ChartEditor) {
+    let digits = [Key::Num0, Key::Num1, Key::Num2, Key::Num3, Key::Num4, Key::Num5, Key::Num6, Key::Num7, Key::Num8, Key::Num9];
+
+    ctx.input_mut(|i| {
+        if i.consume_key(Modifiers::COMMAND, Key::S) {
+            save(editor);
+        }
+        if i.consume_key(Modifiers::COMMAND | Modifiers::SHIFT, Key::Z) || i.consume_key(Modifiers::COMMAND, Key::Y) {
+            editor.redo();
+        }
+        if i.consume_key(Modifiers::COMMAND, Key::Z) {
+            editor.undo();
+        }
+        if i.consume_key(Modifiers::COMMAND, Key::C) {
+            editor.copy_measure();
+        }
+        if i.consume_key(Modifiers::COMMAND, Key::V) {
+            editor.paste_measure();
+        }
+
+        for (digit, key) in digits.iter().enumerate() {
+            if i.consume_key(Modifiers::NONE, *key) {
+                editor.type_digit(digit as u32);
+            }
+        }
+
+        let mut moved = false;
+        let step = 1.0 / editor.grid as f32;
+        if i.consume_key(Modifiers::NONE, Key::ArrowLeft) {
+            let beat = editor.snap(editor.playhead) - step;
+            editor.seek(beat);
+            moved = true;
+        }
+        if i.consume_key(Modifiers::NONE, Key::ArrowRight) {
+            let beat = editor.snap(editor.playhead) + step;
+            editor.seek(beat);
+            moved = true;
+        }
+        if i.consume_key(Modifiers::NONE, Key::ArrowUp) {
+            let lane = tab_to_lane(editor.lane) as usize;
+            editor.lane = lane_to_tab(lane.saturating_sub(1)).unwrap();
+            moved = true;
+        }
+        if i.consume_key(Modifiers::NONE, Key::ArrowDown) {
+            let lane = tab_to_lane(editor.lane) as usize;
+            editor.lane = lane_to_tab((lane + 1).min(5)).unwrap();
+            moved = true;
+        }
+        if i.consume_key(Modifiers::NONE, Key::Enter) {
+            let (tab, beat) = (editor.lane, editor.snap(editor.playhead));
+            editor.place(tab, beat);
+            moved = true;
+        }
+        if i.consume_key(Modifiers::NONE, Key::Delete) || i.consume_key(Modifiers::NONE, Key::Backspace) {
+            let (tab, beat) = (editor.lane, editor.snap(editor.playhead));
+            editor.remove(tab, beat);
+            moved = true;
+        }
+        if i.consume_key(Modifiers::NONE, Key::Space) {
+            toggle_playback(editor);
+            moved = true;
+        }
+        if moved {
+            editor.typing_fret = false;
+        }
+    });
+}
+
+#[inline]
+fn toggle_playback(editor: &mut ChartEditor) {
+    editor.playing = !editor.playing;
+    editor.restart_playback = editor.playing;
+}
+
+#[inline]
+fn save(editor: &mut ChartEditor) {
+    editor.status = match editor.save() {
+        Ok(()) => format!("Saved {}", editor.path),
+        Err(e) => format!("Failed to save: {}", e),
+    };
+}
@@ -1,7 +1,10 @@
-use bevy::{prelude::*, time::Stopwatch};
+use std::{collections::HashMap, time::Duration};
+
+use bevy::{audio::Volume, prelude::*, time::Stopwatch};
 use bevy_egui::{egui, EguiContexts};
+use serde::{Deserialize, Serialize};
 
-use crate::{mic::{MIRIntruction, MagnitudeSpectrum, Mic}, songs::{Note, Song, Tab}, GameState, HEIGHT, WIDTH};
+use crate::{audio::{MetronomeClicks, Pluck}, calibration::InputProfile, mic::{MIRIntruction, MagnitudeSpectrum, Mic}, session::SessionRecorder, songs::{midi_to_name, midi_to_pitch, Note, Song, Tab}, transcribe::{estimate_pitch, HIGHEST_MIDI, LOWEST_MIDI}, tuner::{interpolate_peak, Tuner, Tuning}, GameState, HEIGHT, WIDTH};
 
 
 pub const NOTE_RADIUS: f32 = 25.0;
@@ -14,16 +17,45 @@ pub const DESPAWN_Y_POS: f32 = -SPAWN_Y_POS;
 pub const SCROLL_TIME: f32 = 3.25;
 pub const COLUMN_SPACE: f32 = WIDTH/7.0;
 
-pub const SCORE_THRESHOLD: f32 = 40.0;
+/// In dB above the noise floor.
+pub const SCORE_THRESHOLD: f32 = 12.0;
 
 pub const HIT_FORGIVENESS: f32 = 0.20;
+/// How far outside [`HIT_FORGIVENESS`] a note still counts as played early or late, rather than not at all.
+pub const TIMING_WINDOW: f32 = 0.20;
+/// How far a score has to climb from the quietest frame before it for the note to count as freshly played,
+/// rather than still ringing from before.
+pub const ONSET_RISE: f32 = 3.0;
+pub const MISTAKES_SHOWN: usize = 5;
+
+/// How much each harmonic of a note counts towards its score, from the fundamental up.
+pub const SCORE_HARMONIC_WEIGHTS: [f32; 4] = [1.0, 0.8, 0.6, 0.4];
+/// A harmonic this far above the noise floor is taken as really sounding.
+pub const HARMONIC_EVIDENCE_DB: f32 = 6.0;
+/// How much more a rival fundamental's own harmonics need to stand out to win over the note.
+pub const RIVAL_MARGIN_DB: f32 = 3.0;
+pub const HARMONIC_TOLERANCE: f32 = 0.03;
+
+pub const GUIDE_NOTE_DURATION: Duration = Duration::from_millis(1500);
 
 pub struct GamePlugin;
 
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
-        app .add_systems(OnExit(GameState::SongPlaying), (despawn_all::<Note>, despawn_all::<Backing>))
-            .add_systems(Update, (update_stopwatch, rhythm_calculator, note_animator, display_game).chain().run_if(in_state(GameState::SongPlaying)))
+        app .add_event::<PlaySong>()
+            .init_resource::<Metronome>()
+            .init_resource::<ReferenceGuide>()
+            .add_systems(Update, play_song.run_if(in_state(GameState::Settings)))
+            .add_systems(Update, loading.run_if(in_state(GameState::SongLoading)))
+            .add_systems(OnExit(GameState::SongLoading), (setup_count_in, start_tuning_estimate))
+            .add_systems(OnEnter(GameState::PostSongInfo), (despawn_all::<Note>, despawn_all::<Backing>))
+            .add_systems(OnEnter(GameState::Settings), (despawn_all::<Note>, despawn_all::<Backing>))
+            .add_systems(OnEnter(GameState::Paused), pause_backing)
+            .add_systems(OnExit(GameState::Paused), resume_backing)
+            .add_systems(Update, (update_stopwatch, reference_guide, rhythm_calculator, note_animator, display_game, tuning_hud).chain().run_if(in_state(GameState::SongPlaying)))
+            .add_systems(Update, pause_game.run_if(in_state(GameState::SongPlaying)))
+            .add_systems(Update, (display_game, pause_menu).run_if(in_state(GameState::Paused)))
+            .add_systems(Update, resync_mic_clock.run_if(resource_changed::<Mic>.and_then(in_state(GameState::SongPlaying).or_else(in_state(GameState::Paused)))))
             .add_systems(Update, post_game_info.run_if(in_state(GameState::PostSongInfo)));
     }
 }
@@ -31,13 +63,77 @@ impl Plugin for GamePlugin {
 #[derive(Component)]
 pub struct Backing;
 
+#[derive(Resource)]
+pub struct Metronome {
+    pub enabled: bool,
+    pub volume: f32,
+    pub count_in_bars: u32,
+}
+
+impl Default for Metronome {
+    fn default() -> Self {
+        Metronome {
+            enabled: true,
+            volume: 0.5,
+            count_in_bars: 1,
+        }
+    }
+}
+
+/// Plays the chart as plucked strings so the player can learn it by ear.
+#[derive(Resource)]
+pub struct ReferenceGuide {
+    pub enabled: bool,
+    pub volume: f32,
+    /// Stays quiet while the player keeps hitting notes, and comes back after a miss.
+    pub mute_while_hitting: bool,
+    /// Inclusive ranges of bars, counted from 1, where the guide is muted.
+    pub muted_bars: Vec<(u32, u32)>,
+}
+
+impl Default for ReferenceGuide {
+    fn default() -> Self {
+        ReferenceGuide {
+            enabled: false,
+            volume: 0.5,
+            mute_while_hitting: true,
+            muted_bars: Vec::new(),
+        }
+    }
+}
+
+impl ReferenceGuide {
+    pub fn is_muted(&self, bar: u32) -> bool {
+        self.muted_bars.iter().any(|(from, to)| *from <= bar && bar <= *to)
+    }
+
+    pub fn play(&self, commands: &mut Commands, plucks: &mut Assets<Pluck>, note: &Note) {
+        commands.spawn(AudioSourceBundle {
+            source: plucks.add(Pluck::new(note.pitch(), GUIDE_NOTE_DURATION)),
+            settings: PlaybackSettings::DESPAWN.with_volume(Volume::new(self.volume)),
+        });
+    }
+}
+
 #[derive(Resource)]
 pub struct CurrentSong {
     latest_unplayed_note: usize,
     pub asset: Handle<Song>,
     pub speed: f32,
     stopwatch: Stopwatch,
+    lead_in: f32,
+    next_click: f32,
+    next_guide_note: usize,
+    streak: usize,
+    paused_at: f32,
+    paused_for: f32,
+    /// When the mic clock started, so a stream reconnected mid-song can be put back on it.
+    mic_started_at: Option<f32>,
     success: usize,
+    wrong_octave: usize,
+    shared_harmonic: usize,
+    mistakes: HashMap<Mistake, usize>,
+    results: Vec<NoteResult>,
 }
 
 impl CurrentSong {
@@ -45,42 +141,249 @@ impl CurrentSong {
         CurrentSong {
             asset,
             stopwatch: Stopwatch::new(),
+            lead_in: 0.0,
+            next_click: 0.0,
+            next_guide_note: 0,
+            streak: 0,
+            paused_at: 0.0,
+            paused_for: 0.0,
+            mic_started_at: None,
             latest_unplayed_note: 0,
             success: 0,
+            wrong_octave: 0,
+            shared_harmonic: 0,
+            mistakes: HashMap::new(),
+            results: Vec::new(),
             speed,
         }
     }
+
+    /// Whether the mic clock has started, which happens with the first beat of the song.
+    pub fn mic_started(&self) -> bool {
+        self.mic_started_at.is_some()
+    }
+
+    /// Every note judged so far, in the order they were.
+    pub fn results(&self) -> &[NoteResult] {
+        &self.results
+    }
+
+    /// How far the mic clock has run ahead of the chart from pauses.
+    pub fn paused_for(&self) -> f32 {
+        self.paused_for
+    }
+
+    /// Position in the chart in seconds, negative during the count-in.
+    pub fn song_time(&self) -> f32 {
+        self.stopwatch.elapsed_secs() * self.speed - self.lead_in
+    }
 }
 
-#[derive(Default, Component)]
+#[derive(Default, Clone, Component, Serialize, Deserialize)]
 pub struct NoteHitData {
-    pub data: Vec<(f32, f32)>
+    pub data: Vec<(f32, f32)>,
+    /// SNR and cents from the reference of the strongest peak near the note, for tuning detection.
+    pub tuning_estimate: Option<(f32, f32)>,
+    /// Why a frame that would have hit was rejected.
+    pub confusion: Option<Confusion>,
+    /// The strongest pitch heard in each frame of the hit window, as a MIDI note and its salience.
+    pub played: Vec<(u32, f32)>,
+    /// Whether the hit window has passed, leaving only the chance to have been late.
+    pub window_closed: bool,
+}
+
+impl NoteHitData {
+    /// The first frame inside the hit window to beat `threshold`, as its offset and score.
+    pub fn hit_frame(&self, threshold: f32) -> Option<(f32, f32)> {
+        self.data.iter().copied().find(|(diff, score)| diff.abs() <= HIT_FORGIVENESS && *score > threshold)
+    }
+
+    /// Highest score inside the hit window.
+    pub fn best_score(&self) -> Option<f32> {
+        self.data.iter().filter(|(diff, _)| diff.abs() <= HIT_FORGIVENESS).map(|(_, score)| *score).reduce(f32::max)
+    }
+
+    /// Offsets of the frames where the note was played: over `threshold`, and at least [`ONSET_RISE`]
+    /// above the quietest frame before. A note that was already sounding when listening began has no onset.
+    pub fn onsets(&self, threshold: f32) -> impl Iterator<Item = f32> + '_ {
+        self.data.iter().scan(f32::INFINITY, move |quietest, (diff, score)| {
+            let onset = *score > threshold && *score - *quietest >= ONSET_RISE;
+            *quietest = quietest.min(*score);
+            Some(onset.then_some(*diff))
+        }).flatten()
+    }
+
+    /// Works out why a note was missed, from everything heard around it.
+    pub fn classify(&self, threshold: f32, expected: u32) -> Miss {
+        // Only a note played outside the window is early or late, not one still ringing from before
+        if self.onsets(threshold).any(|diff| diff < -HIT_FORGIVENESS) {
+            return Miss::Early;
+        }
+        if self.onsets(threshold).any(|diff| diff > HIT_FORGIVENESS) {
+            return Miss::Late;
+        }
+        match self.played.iter().copied().filter(|(_, salience)| *salience > SCORE_THRESHOLD).reduce(|a, b| if a.1 >= b.1 { a } else { b }) {
+            Some((midi, _)) if midi != expected => Miss::WrongPitch(midi),
+            _ => Miss::Silent,
+        }
+    }
+}
+
+/// How one note of the chart was judged, with everything heard around it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NoteResult {
+    pub note: Note,
+    pub hit: bool,
+    /// Why the note was missed, or nothing for a hit or a note the song ended before judging.
+    pub miss: Option<Miss>,
+    pub hit_data: NoteHitData,
+    /// The score the note had to beat.
+    pub threshold: f32,
+    /// How far the mic clock had run ahead of the chart from pauses, when the note was judged.
+    pub paused_for: f32,
+}
+
+impl NoteResult {
+    /// When the note was due on the mic clock, which a recorded session shares.
+    pub fn mic_time(&self, bpm: f32, speed: f32) -> f32 {
+        note_mic_time(&self.note, bpm, speed) + self.paused_for
+    }
+}
+
+/// When a note is due on the mic clock, leaving out pauses. The mic hears in real time, so slowing the song down
+/// spreads its notes out.
+#[inline]
+pub fn note_mic_time(note: &Note, bpm: f32, speed: f32) -> f32 {
+    note.beat / (bpm / 60.0) / speed
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Miss {
+    Silent,
+    /// Another note was played, given as its MIDI number.
+    WrongPitch(u32),
+    Early,
+    Late,
+}
+
+/// One kind of miss on one note of the chart, which repeats are counted against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Mistake {
+    pub bar: u32,
+    pub expected: u32,
+    pub miss: Miss,
 }
 
-fn despawn_all<T: Component>(mut commands: Commands, notes: Query<Entity, With<T>>) {
+impl std::fmt::Display for Mistake {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let expected = midi_to_name(self.expected);
+        match self.miss {
+            Miss::Silent => write!(f, "Nothing heard for {} in bar {}", expected, self.bar),
+            Miss::WrongPitch(played) => write!(f, "You played {} instead of {} in bar {}", midi_to_name(played), expected, self.bar),
+            Miss::Early => write!(f, "{} in bar {} was early", expected, self.bar),
+            Miss::Late => write!(f, "{} in bar {} was late", expected, self.bar),
+        }
+    }
+}
+
+/// Another note that explains what was heard better than the expected one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Confusion {
+    WrongOctave,
+    SharedHarmonic,
+}
+
+pub struct NoteScore {
+    pub score: f32,
+    /// The score had no rival been considered.
+    pub unpenalized: f32,
+    pub confusion: Option<Confusion>,
+}
+
+pub fn despawn_all<T: Component>(mut commands: Commands, notes: Query<Entity, With<T>>) {
     for e in notes.iter() {
         commands.entity(e).despawn_recursive();
     }
 }
 
+/// Asks for the chart at `path`, relative to the assets, to be loaded and played at `speed`.
+#[derive(Event)]
+pub struct PlaySong {
+    pub path: String,
+    pub speed: f32,
+}
+
+fn play_song(
+    mut commands: Commands,
+    mut requests: EventReader<PlaySong>,
+    mut next_state: ResMut<NextState<GameState>>,
+    asset_server: Res<AssetServer>,
+) {
+    if let Some(request) = requests.read().last() {
+        commands.insert_resource(CurrentSong::new(asset_server.load(request.path.clone()), request.speed));
+        next_state.set(GameState::SongLoading);
+    }
+}
+
+fn loading(
+    mut next_state: ResMut<NextState<GameState>>,
+    asset_server: Res<AssetServer>,
+    song: Res<CurrentSong>,
+) {
+    if asset_server.is_loaded_with_dependencies(&song.asset) {
+        next_state.set(GameState::SongPlaying);
+    }
+}
+
+fn setup_count_in(
+    songs: Res<Assets<Song>>,
+    metronome: Res<Metronome>,
+    mut song_data: ResMut<CurrentSong>,
+) {
+    let song = songs.get(&song_data.asset).unwrap();
+    let count_in_beats = (metronome.count_in_bars * song.beats_per_bar) as f32;
+
+    song_data.lead_in = count_in_beats / (song.bpm / 60.0);
+    song_data.next_click = -count_in_beats;
+}
+
+fn start_tuning_estimate(mut tuning: ResMut<Tuning>) {
+    tuning.start_estimate();
+}
+
 fn update_stopwatch(
     mut commands: Commands,
     time: Res<Time>,
     songs: Res<Assets<Song>>,
     mut song_data: ResMut<CurrentSong>,
     mic: Res<Mic>,
+    metronome: Res<Metronome>,
+    clicks: Res<MetronomeClicks>,
 ) {
-    let prev_time = song_data.stopwatch.elapsed_secs();
+    let prev_time = song_data.song_time();
     song_data.stopwatch.tick(time.delta());
-    let this_time = song_data.stopwatch.elapsed_secs();
+    let this_time = song_data.song_time();
+
+    let song = songs.get(&song_data.asset).unwrap();
+
+    // Clicks are scheduled on the song clock, so they stay on the beat at any speed
+    let this_beat = this_time * song.bpm / 60.0;
+    while song_data.next_click <= this_beat {
+        let beat = song_data.next_click;
+        if metronome.enabled || beat < 0.0 {
+            let accent = (beat as i32).rem_euclid(song.beats_per_bar as i32) == 0;
+            clicks.spawn(&mut commands, accent, metronome.volume);
+        }
+        song_data.next_click += 1.0;
+    }
     
     if prev_time <= 0.0 && 0.0 <= this_time {
 
         if let Some(sender) = &mic.mir_sender {
             let _ = sender.send(MIRIntruction::SongStart);
         }
+        song_data.mic_started_at = Some(time.elapsed_seconds());
 
-        let song = songs.get(&song_data.asset).unwrap();
         if let Some(backing) = &song.backing {
             commands.spawn((
                 AudioSourceBundle {
@@ -93,8 +396,98 @@ fn update_stopwatch(
     }
 }
 
+fn reference_guide(
+    mut commands: Commands,
+    songs: Res<Assets<Song>>,
+    guide: Res<ReferenceGuide>,
+    mut plucks: ResMut<Assets<Pluck>>,
+    mut song_data: ResMut<CurrentSong>,
+) {
+    let song = songs.get(&song_data.asset).unwrap();
+    let this_beat = song_data.song_time() * song.bpm / 60.0;
+
+    while let Some(note) = song.notes.get(song_data.next_guide_note) {
+        if note.beat > this_beat {
+            break;
+        }
+        song_data.next_guide_note += 1;
+
+        let bar = (note.beat / song.beats_per_bar as f32) as u32 + 1;
+        let hitting = guide.mute_while_hitting && song_data.streak > 0;
+        if guide.enabled && !hitting && !guide.is_muted(bar) {
+            guide.play(&mut commands, &mut plucks, note);
+        }
+    }
+}
+
+fn pause_game(keys: Res<ButtonInput<KeyCode>>, mut next_state: ResMut<NextState<GameState>>) {
+    if keys.just_pressed(KeyCode::Escape) {
+        next_state.set(GameState::Paused);
+    }
+}
+
+fn pause_backing(
+    time: Res<Time>,
+    backing: Query<&AudioSink, With<Backing>>,
+    mut song_data: ResMut<CurrentSong>,
+) {
+    for sink in backing.iter() {
+        sink.pause();
+    }
+    song_data.paused_at = time.elapsed_seconds();
+}
+
+fn resume_backing(
+    time: Res<Time>,
+    backing: Query<&AudioSink, With<Backing>>,
+    mut song_data: ResMut<CurrentSong>,
+) {
+    for sink in backing.iter() {
+        sink.play();
+    }
+
+    // The mic clock only starts with the song, so pauses during the count-in don't shift it
+    if song_data.song_time() > 0.0 {
+        song_data.paused_for += time.elapsed_seconds() - song_data.paused_at;
+    }
+}
+
+/// Puts a stream reconnected mid-song back on the song's clock.
+fn resync_mic_clock(mic: Res<Mic>, time: Res<Time>, song_data: Res<CurrentSong>) {
+    if let (Some(sender), Some(started)) = (&mic.mir_sender, song_data.mic_started_at) {
+        let _ = sender.send(MIRIntruction::SongResume(Duration::from_secs_f32(time.elapsed_seconds() - started)));
+    }
+}
+
+fn pause_menu(
+    mut contexts: EguiContexts,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut tuner: ResMut<Tuner>,
+) {
+    if keys.just_pressed(KeyCode::Escape) {
+        next_state.set(GameState::SongPlaying);
+    }
+
+    egui::Window::new("Paused")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(contexts.ctx_mut(), |ui| {
+            if ui.button("Resume").clicked() {
+                next_state.set(GameState::SongPlaying);
+            }
+            if ui.button("Tuner").clicked() {
+                tuner.open = !tuner.open;
+            }
+            if ui.button("Main Menu").clicked() {
+                next_state.set(GameState::Settings);
+            }
+        });
+}
+
 #[inline]
-fn tab_to_column(tab: Tab) -> f32 {
+pub fn tab_to_column(tab: Tab) -> f32 {
     -(WIDTH/2.0) + COLUMN_SPACE * match tab {
         Tab::E2 => 1.0,
         Tab::A2 => 2.0,
@@ -114,7 +507,7 @@ fn note_animator(
 ) {
     let song = songs.get(&song_data.asset).unwrap();
     
-    let elapsed_time = song_data.stopwatch.elapsed_secs() * song_data.speed;
+    let elapsed_time = song_data.song_time();
     let bps = song.bpm / 60.0;
 
     if song_data.latest_unplayed_note >= song.notes.len() && notes.is_empty() {
@@ -138,11 +531,7 @@ fn note_animator(
         }
     }
 
-    loop {
-        let Some(note) = song.notes.get(song_data.latest_unplayed_note) else { 
-            break;
-        };
-
+    while let Some(note) = song.notes.get(song_data.latest_unplayed_note) {
         let hit_time = note.beat / bps;
         let spawn_time = hit_time - SCROLL_TIME;
         let p = (elapsed_time - spawn_time) / SCROLL_TIME;
@@ -188,85 +577,173 @@ fn note_animator(
 
 fn rhythm_calculator(
     mut commands: Commands,
-    mic: Res<Mic>,
+    mut spectra: EventReader<MagnitudeSpectrum>,
     mut notes: Query<(Entity, &Note, &mut NoteHitData)>,
     mut song_data: ResMut<CurrentSong>,
+    mut tuning: ResMut<Tuning>,
+    profile: Res<InputProfile>,
     songs: Res<Assets<Song>>,
 ) {
-    let bps = songs.get(&song_data.asset).unwrap().bpm / 60.0;
+    let song = songs.get(&song_data.asset).unwrap();
 
-    if let Some(mir_receiver) = &mic.mir_receiver {
-        while let Ok(fft_info) = mir_receiver.try_recv() {
-            for (e, note, mut note_hit_data) in notes.iter_mut() {
-                let note_time = note.beat / bps * song_data.speed; 
+    for fft_info in spectra.read() {
+        // What was played is the same for every note, so it's only worked out when a note needs it
+        let mut played = None;
+
+        for (e, note, mut note_hit_data) in notes.iter_mut() {
+            let note_time = note_mic_time(note, song.bpm, song_data.speed);
+
+            let diff = fft_info.progress.as_secs_f32() - song_data.paused_for - note_time;
+            let threshold = profile.threshold(note.tab, note.fret);
+            
+            if diff > HIT_FORGIVENESS + TIMING_WINDOW {
+                commands.entity(e).remove::<NoteHitData>();
+
+                let miss = note_hit_data.classify(threshold, note.midi());
+                let mistake = Mistake {
+                    bar: (note.beat / song.beats_per_bar as f32) as u32 + 1,
+                    expected: note.midi(),
+                    miss,
+                };
+                *song_data.mistakes.entry(mistake).or_insert(0) += 1;
+                debug!(note = %midi_to_name(note.midi()), beat = note.beat, ?miss, best = note_hit_data.best_score(), threshold, "Note missed");
+                let hit_data = std::mem::take(&mut *note_hit_data);
+                let paused_for = song_data.paused_for;
+                song_data.results.push(NoteResult { note: note.clone(), hit: false, miss: Some(miss), hit_data, threshold, paused_for });
+                continue;
+            }
+            else if diff > HIT_FORGIVENESS && !note_hit_data.window_closed {
+                note_hit_data.window_closed = true;
+
+                let hit_frame = note_hit_data.hit_frame(threshold);
+                let hit = hit_frame.is_some();
+                if let Some((diff, score)) = hit_frame {
+                    debug!(note = %midi_to_name(note.midi()), beat = note.beat, diff, score, threshold, "Note hit");
+                    commands.entity(e).despawn_recursive();
+                    song_data.success += 1;
+                }
+                song_data.streak = if hit { song_data.streak + 1 } else { 0 };
 
-                let diff = fft_info.progress.as_secs_f32() - note_time;
-                
-                if diff > HIT_FORGIVENESS {
-                    commands.entity(e).remove::<NoteHitData>();
+                match note_hit_data.confusion.filter(|_| !hit) {
+                    Some(Confusion::WrongOctave) => song_data.wrong_octave += 1,
+                    Some(Confusion::SharedHarmonic) => song_data.shared_harmonic += 1,
+                    None => {},
+                }
 
-                    // println!("\nScores for note {:?}", note);
-                    for (diff, score) in note_hit_data.data.iter() {
-                        println!("{:.0} at diff {:.6}", score.floor(), *diff);
-                        if *score > SCORE_THRESHOLD {
-                            println!("Note {:?} Hit!", note);
-                            commands.entity(e).despawn_recursive();
-                            song_data.success += 1;
-                            break;
-                        }
+                if let Some((snr, cents)) = note_hit_data.tuning_estimate {
+                    if snr > SCORE_THRESHOLD {
+                        tuning.add_estimate(cents);
                     }
-
-                    // println!("\nScore differences :");
-                    // for (d, s) in note_hit_data.data.windows(2).map(|slice| (slice[0].0, slice[0].1 - slice[1].1)) {
-                    //     println!("{:.0} at diff {:.6}", s.floor(), d); 
-                    // }
-
-                    continue;
                 }
-                else if diff < -HIT_FORGIVENESS {
+
+                if hit {
+                    commands.entity(e).remove::<NoteHitData>();
+                    let hit_data = std::mem::take(&mut *note_hit_data);
+                    let paused_for = song_data.paused_for;
+                    song_data.results.push(NoteResult { note: note.clone(), hit: true, miss: None, hit_data, threshold, paused_for });
                     continue;
                 }
+            }
+            else if diff < -(HIT_FORGIVENESS + TIMING_WINDOW) {
+                continue;
+            }
 
-                let score = calculate_score(note.pitch(), &fft_info);
+            let pitch = tuning.adjust(note.pitch());
+            let NoteScore { score, unpenalized, confusion } = score_note(pitch, fft_info, &profile);
+            note_hit_data.data.push((diff, score));
+            trace!(note = %midi_to_name(note.midi()), beat = note.beat, diff, score, "Scored frame");
 
+            if diff.abs() > HIT_FORGIVENESS {
+                continue;
+            }
+
+            if unpenalized > threshold {
+                note_hit_data.confusion = note_hit_data.confusion.or(confusion);
+            }
 
-                note_hit_data.data.push((diff, score));
+            let heard = *played.get_or_insert_with(|| estimate_pitch(fft_info));
+            note_hit_data.played.push(heard);
 
+            if tuning.is_estimating() {
+                if let Some((peak, _)) = interpolate_peak(fft_info, pitch) {
+                    // Measured in the log bins, as that's what the noise floor is learnt from
+                    let snr = fft_info.pitch_snr_db(peak);
+                    if note_hit_data.tuning_estimate.map_or(true, |(best, _)| snr > best) {
+                        let cents = 1200.0 * (peak / tuning.reference_pitch(note.pitch())).log2();
+                        note_hit_data.tuning_estimate = Some((snr, cents));
+                    }
+                }
             }
         }
     }
 }
 
-pub fn calculate_score(pitch: f32, spectrum: &MagnitudeSpectrum) -> f32 {
-    let first_harm = pitch;
-    let second_harm = 2.0*first_harm;
-    let third_harm = 3.0*first_harm;
+/// How far the note's harmonics stand above the noise floor in the log-frequency spectrum, in dB,
+/// normalized by the device's calibration.
+pub fn calculate_score(pitch: f32, spectrum: &MagnitudeSpectrum, profile: &InputProfile) -> f32 {
+    score_note(pitch, spectrum, profile).score
+}
 
-    // let score = (
-    //     spectrum.amplitude_at(first_harm).powi(4)
-    //     * spectrum.amplitude_at(second_harm).powi(2)
-    //     * spectrum.amplitude_at(third_harm)
-    // ).powf(1.0/7.0);
+#[inline]
+fn is_harmonic(pitch: f32, fundamental: f32) -> bool {
+    let ratio = pitch / fundamental;
+    ratio.round() >= 1.0 && (ratio - ratio.round()).abs() < HARMONIC_TOLERANCE
+}
 
-    // let score = (
-    //     spectrum.approx_amplitude_at(first_harm).powi(2)
-    //     * spectrum.approx_amplitude_at(second_harm)
-    //     * spectrum.approx_amplitude_at(third_harm)
-    // ).powf(0.25);
+/// Scores the weighted harmonic profile of a note, unless a different fundamental sharing some of
+/// those harmonics explains the frame better, in which case it scores nothing.
+///
+/// A rival wins if its own harmonics sound clearly louder than the note's own. A rival below the note, like an
+/// octave or a fifth down, shares all of the note's harmonics, so those are measured by the note's weighted
+/// score instead. A rival that is a multiple of the note has no harmonics of its own, so it wins instead when
+/// the note's other harmonics are missing.
+pub fn score_note(pitch: f32, spectrum: &MagnitudeSpectrum, profile: &InputProfile) -> NoteScore {
+    let harmonics = |fundamental: f32| (1..=SCORE_HARMONIC_WEIGHTS.len()).map(move |h| h as f32 * fundamental);
+    let loudest = |fundamental: f32, keep: &dyn Fn(f32) -> bool| harmonics(fundamental)
+        .filter(|h| keep(*h))
+        .map(|h| spectrum.pitch_snr_db(h))
+        .fold(0.0, f32::max);
+
+    let weighted = harmonics(pitch).zip(SCORE_HARMONIC_WEIGHTS).map(|(h, w)| w * spectrum.pitch_snr_db(h)).sum::<f32>()
+        / SCORE_HARMONIC_WEIGHTS.iter().sum::<f32>();
+    let unpenalized = weighted + profile.gain_db;
+
+    let (lowest, highest) = (midi_to_pitch(LOWEST_MIDI), midi_to_pitch(HIGHEST_MIDI));
+    let count = SCORE_HARMONIC_WEIGHTS.len();
+    let rival = (1..=count)
+        .flat_map(|a| (1..=count).filter(move |b| *b != a).map(move |b| pitch * a as f32 / b as f32))
+        .filter(|rival| (lowest..=highest).contains(rival))
+        .find(|rival| {
+            let own = loudest(pitch, &|h| !is_harmonic(h, *rival));
+            if is_harmonic(*rival, pitch) {
+                let shared = loudest(pitch, &|h| is_harmonic(h, *rival));
+                own < HARMONIC_EVIDENCE_DB && shared >= HARMONIC_EVIDENCE_DB
+            } else {
+                // A lower note still ringing shouldn't outweigh the note when it's clearly sounding too
+                loudest(*rival, &|h| !is_harmonic(h, pitch)) > own.max(weighted) + RIVAL_MARGIN_DB
+            }
+        });
 
-    // let score = (
-    //     4.0*spectrum.amplitude_at(first_harm)
-    //     + 2.0*spectrum.amplitude_at(second_harm)
-    //     + spectrum.amplitude_at(third_harm)
-    // ) / 7.0;
+    let confusion = rival.map(|rival| {
+        let octaves = (rival / pitch).log2();
+        if (octaves - octaves.round()).abs() < HARMONIC_TOLERANCE { Confusion::WrongOctave } else { Confusion::SharedHarmonic }
+    });
 
-    let score = spectrum.approx_amplitude_at(first_harm);
-    
-    // let score = spectrum.amplitude_at(first_harm);
-    // let score = score / spectrum.mean_squared.sqrt(); //.max(0.05);
-    // let score = score / spectrum.rms.sqrt().max(0.05);
+    NoteScore {
+        score: if confusion.is_some() { 0.0 } else { unpenalized },
+        unpenalized,
+        confusion,
+    }
+}
 
-    score
+fn tuning_hud(mut contexts: EguiContexts, tuning: Res<Tuning>) {
+    egui::Area::new(egui::Id::new("tuning_hud"))
+        .anchor(egui::Align2::RIGHT_TOP, egui::Vec2::new(-10.0, 10.0))
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(format!("A4 = {:.0} Hz", tuning.reference));
+            let detecting = if tuning.is_estimating() { " (detecting)" } else { "" };
+            ui.label(format!("Tuning offset: {:+.0} cents{}", tuning.offset_cents, detecting));
+        });
 }
 
 fn display_game(mut gizmos: Gizmos, notes: Query<&mut Transform, With<Note>>) {
@@ -288,6 +765,7 @@ fn post_game_info(
     mut next_state: ResMut<NextState<GameState>>,
     song_data: Res<CurrentSong>,
     songs: Res<Assets<Song>>,
+    session: Res<SessionRecorder>,
 ) {
 
     let ctx = contexts.ctx_mut();
@@ -302,6 +780,31 @@ fn post_game_info(
         let total_notes = songs.get(&song_data.asset).unwrap().notes.len();
         ui.label(format!("Total notes: {}", total_notes));
 
+        ui.label(format!("Right note, wrong octave: {}", song_data.wrong_octave));
+        ui.label(format!("Wrong note sharing a harmonic: {}", song_data.shared_harmonic));
+
+        let count = |kind: fn(&Miss) -> bool| song_data.mistakes.iter().filter(|(m, _)| kind(&m.miss)).map(|(_, n)| n).sum::<usize>();
+        ui.label(format!("Silent: {}", count(|m| *m == Miss::Silent)));
+        ui.label(format!("Wrong pitch: {}", count(|m| matches!(m, Miss::WrongPitch(_)))));
+        ui.label(format!("Early: {}", count(|m| *m == Miss::Early)));
+        ui.label(format!("Late: {}", count(|m| *m == Miss::Late)));
+
+        let mut mistakes = song_data.mistakes.iter().collect::<Vec<_>>();
+        mistakes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.bar.cmp(&b.0.bar)));
+        if !mistakes.is_empty() {
+            ui.separator();
+            ui.heading("Most frequent mistakes");
+            for (mistake, times) in mistakes.into_iter().take(MISTAKES_SHOWN) {
+                let plural = if *times == 1 { "time" } else { "times" };
+                ui.label(format!("{} ({} {})", mistake, times, plural));
+            }
+        }
+
+        if !session.status.is_empty() {
+            ui.separator();
+            ui.label(&session.status);
+        }
+
         ui.separator();
         if ui.button("Main Menu").clicked() {
             next_state.set(GameState::Settings);
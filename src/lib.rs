@@ -1,8 +1,20 @@
 use bevy::ecs::schedule::States;
 
+pub mod audio;
+pub mod calibration;
+pub mod detector_debug;
+pub mod editor;
+pub mod meter;
 pub mod mic;
+pub mod monitor;
+pub mod replay;
+pub mod ring;
+pub mod session;
 pub mod settings;
 pub mod songs;
+pub mod spectrogram;
+pub mod transcribe;
+pub mod tuner;
 pub mod game;
 
 pub const WIDTH: f32 = 1000.0;
@@ -14,5 +26,9 @@ pub enum GameState {
     Settings,
     SongLoading,
     SongPlaying,
-    PostSongInfo
+    Paused,
+    PostSongInfo,
+    Editor,
+    Recording,
+    Replay,
 }
\ No newline at end of file
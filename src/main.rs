@@ -1,6 +1,6 @@
 use bevy::{prelude::*, window::WindowResolution};
 use mir_project::{
-    game::GamePlugin, mic::MicPlugin, settings::SettingsUiPlugin, songs::SongPlugin, GameState, HEIGHT, WIDTH
+    audio::AudioSourcesPlugin, calibration::CalibrationPlugin, detector_debug::DetectorDebugPlugin, editor::EditorPlugin, game::GamePlugin, meter::MeterPlugin, mic::MicPlugin, monitor::MonitorPlugin, replay::ReplayPlugin, session::SessionPlugin, settings::SettingsUiPlugin, songs::SongPlugin, spectrogram::SpectrogramPlugin, transcribe::TranscribePlugin, tuner::TunerPlugin, GameState, HEIGHT, WIDTH
 };
 
 
@@ -17,11 +17,21 @@ fn main() {
                 ..default()
             }), 
             bevy_egui::EguiPlugin, 
+            AudioSourcesPlugin,
             MicPlugin, 
             SongPlugin, 
             SettingsUiPlugin,
-            GamePlugin
+            GamePlugin,
+            EditorPlugin,
+            TranscribePlugin,
+            TunerPlugin,
+            CalibrationPlugin,
+            MeterPlugin,
+            MonitorPlugin,
+            SessionPlugin,
+            ReplayPlugin,
         ))
+        .add_plugins((SpectrogramPlugin, DetectorDebugPlugin))
         .add_systems(Startup, setup)
         .init_state::<GameState>()
         .run()
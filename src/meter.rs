@@ -0,0 +1,162 @@
+use bevy::prelude::*;
+use bevy_egui::{egui::{self, Color32, Rounding, Stroke}, EguiContexts};
+
+use crate::{mic::{MagnitudeSpectrum, Mic}, settings::SettingsPanel, GameState};
+
+/// Bottom of the meter scale in dBFS.
+pub const METER_FLOOR_DB: f32 = -60.0;
+/// Peaks at or above this, in dBFS, count as clipping.
+pub const CLIP_DB: f32 = -0.1;
+/// Below this, in dBFS, there's effectively no signal, like from an unplugged cable.
+pub const NO_SIGNAL_DB: f32 = -80.0;
+/// Seconds the held peak stays put before it starts falling.
+pub const PEAK_HOLD_TIME: f32 = 1.5;
+pub const PEAK_FALL_DB_PER_SEC: f32 = 20.0;
+/// Seconds the clipping warning stays lit after the input clips.
+pub const CLIP_HOLD_TIME: f32 = 3.0;
+
+pub const METER_WIDTH: f32 = 240.0;
+pub const HUD_METER_WIDTH: f32 = 120.0;
+pub const METER_HEIGHT: f32 = 12.0;
+
+pub struct MeterPlugin;
+
+impl Plugin for MeterPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<LevelMeter>()
+            .add_systems(Update, update_meter)
+            // Before the settings side panel, so the strip runs the full width of the window
+            .add_systems(Update, settings_meter.after(update_meter).before(SettingsPanel).run_if(in_state(GameState::Settings)))
+            .add_systems(Update, hud_meter.after(update_meter).run_if(in_state(GameState::SongPlaying).or_else(in_state(GameState::Paused))));
+    }
+}
+
+/// Input level of the latest spectrum, with a held peak and clipping warning.
+#[derive(Resource)]
+pub struct LevelMeter {
+    pub rms_db: f32,
+    pub peak_db: f32,
+    pub held_peak_db: f32,
+    held_at: f32,
+    clipped_at: Option<f32>,
+    connected: bool,
+}
+
+impl Default for LevelMeter {
+    fn default() -> Self {
+        LevelMeter {
+            rms_db: NO_SIGNAL_DB,
+            peak_db: NO_SIGNAL_DB,
+            held_peak_db: NO_SIGNAL_DB,
+            held_at: 0.0,
+            clipped_at: None,
+            connected: false,
+        }
+    }
+}
+
+impl LevelMeter {
+    pub fn clipping(&self, now: f32) -> bool {
+        self.clipped_at.is_some_and(|at| now - at < CLIP_HOLD_TIME)
+    }
+
+    fn update(&mut self, rms_db: f32, peak_db: f32, now: f32) {
+        self.rms_db = rms_db;
+        self.peak_db = peak_db;
+
+        if peak_db >= self.held_peak_db {
+            self.held_peak_db = peak_db;
+            self.held_at = now;
+        }
+        if peak_db >= CLIP_DB {
+            self.clipped_at = Some(now);
+        }
+    }
+
+    /// Lets the held peak fall once it's been held long enough.
+    fn decay(&mut self, now: f32, delta: f32) {
+        if now - self.held_at > PEAK_HOLD_TIME {
+            self.held_peak_db = (self.held_peak_db - PEAK_FALL_DB_PER_SEC * delta).max(self.peak_db);
+        }
+    }
+
+    fn status(&self, now: f32) -> (&'static str, Color32) {
+        if !self.connected {
+            ("No input", Color32::GRAY)
+        } else if self.clipping(now) {
+            ("CLIP", Color32::RED)
+        } else if self.rms_db < NO_SIGNAL_DB {
+            ("No signal", Color32::YELLOW)
+        } else {
+            ("", Color32::WHITE)
+        }
+    }
+}
+
+fn update_meter(
+    mic: Res<Mic>,
+    time: Res<Time>,
+    mut spectra: EventReader<MagnitudeSpectrum>,
+    mut meter: ResMut<LevelMeter>,
+) {
+    let now = time.elapsed_seconds();
+    meter.connected = mic.mir_receiver.is_some();
+    if !meter.connected {
+        spectra.clear();
+        *meter = LevelMeter::default();
+        return;
+    }
+
+    for spectrum in spectra.read() {
+        meter.update(spectrum.level_db(), spectrum.peak_db(), now);
+    }
+    meter.decay(now, time.delta_seconds());
+}
+
+/// Draws the meter as a bar from [`METER_FLOOR_DB`] to 0 dBFS, with RMS filled in over the peak and a line at the held peak.
+pub fn level_meter(ui: &mut egui::Ui, meter: &LevelMeter, now: f32, width: f32) {
+    ui.horizontal(|ui| {
+        let (rect, response) = ui.allocate_exact_size(egui::vec2(width, METER_HEIGHT), egui::Sense::hover());
+        let painter = ui.painter_at(rect);
+        let x = |db: f32| rect.left() + rect.width() * ((db - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0);
+        let fill = |db: f32| egui::Rect::from_min_max(rect.min, egui::pos2(x(db), rect.bottom()));
+        let colour = |db: f32| match db {
+            db if db >= -6.0 => Color32::RED,
+            db if db >= -18.0 => Color32::YELLOW,
+            _ => Color32::GREEN,
+        };
+
+        painter.rect_filled(rect, Rounding::ZERO, Color32::from_gray(30));
+        if meter.connected {
+            painter.rect_filled(fill(meter.peak_db), Rounding::ZERO, colour(meter.peak_db).gamma_multiply(0.4));
+            painter.rect_filled(fill(meter.rms_db), Rounding::ZERO, colour(meter.rms_db));
+            let held = x(meter.held_peak_db);
+            painter.vline(held, rect.y_range(), Stroke::new(2.0, colour(meter.held_peak_db)));
+        }
+        response.on_hover_text(format!(
+            "RMS {:.1} dBFS, peak {:.1} dBFS, held {:.1} dBFS",
+            meter.rms_db, meter.peak_db, meter.held_peak_db,
+        ));
+
+        let (status, colour) = meter.status(now);
+        ui.colored_label(colour, status);
+    });
+}
+
+pub(crate) fn settings_meter(mut contexts: EguiContexts, meter: Res<LevelMeter>, time: Res<Time>) {
+    egui::TopBottomPanel::bottom("level_meter").show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Input");
+            level_meter(ui, &meter, time.elapsed_seconds(), METER_WIDTH);
+            ui.label(format!("RMS {:.0} dBFS  Peak {:.0} dBFS", meter.rms_db, meter.held_peak_db));
+        });
+    });
+}
+
+fn hud_meter(mut contexts: EguiContexts, meter: Res<LevelMeter>, time: Res<Time>) {
+    egui::Area::new(egui::Id::new("hud_meter"))
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::vec2(10.0, -10.0))
+        .show(contexts.ctx_mut(), |ui| {
+            level_meter(ui, &meter, time.elapsed_seconds(), HUD_METER_WIDTH);
+        });
+}
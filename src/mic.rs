@@ -1,19 +1,70 @@
 
-use cpal::{traits::{DeviceTrait, HostTrait, StreamTrait}, BuildStreamError, DefaultStreamConfigError, Device, InputCallbackInfo, SampleFormat, Stream, StreamConfig, StreamInstant};
+use cpal::{traits::{DeviceTrait, HostTrait, StreamTrait}, BuildStreamError, DefaultStreamConfigError, Device, DevicesError, Host, HostId, HostUnavailable, PlayStreamError, SampleFormat, SampleRate, Stream, StreamConfig, StreamError, SupportedBufferSize, SupportedStreamConfig, SupportedStreamConfigRange};
 use bevy::prelude::*;
-use rustfft::{num_complex::{Complex, ComplexFloat}, num_traits::Float, Fft, FftPlanner};
-use std::{collections::VecDeque, fmt::Debug, sync::Arc, time::Duration};
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use rustfft::{num_complex::{Complex, ComplexFloat}, Fft, FftPlanner};
+use std::{collections::VecDeque, fmt::{Debug, Display}, panic::AssertUnwindSafe, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc}, time::Duration};
+use crossbeam_channel::{bounded, select, tick, unbounded, Receiver, Sender};
 
-pub const WINDOW_SIZE: usize = 8192;
-pub const HOP_SIZE: usize = 2048;
+use crate::{ring::{frame_ring, FrameReader, FrameWriter}, songs::pitch_to_midi};
+
+pub const DEFAULT_WINDOW_SIZE: usize = 8192;
+pub const DEFAULT_HOP_SIZE: usize = 2048;
+pub const WINDOW_SIZES: [usize; 5] = [1024, 2048, 4096, 8192, 16384];
+pub const ZERO_PADDING_FACTORS: [usize; 3] = [1, 2, 4];
+pub const GAUSSIAN_SIGMA: f32 = 0.4;
+#[allow(clippy::excessive_precision)]
 pub const PITCH_APPROXIMATION: f32 = 1.005792941; // 10 cents //1.0116194403; // 20 cents 
 
+pub const MIN_BAND_WINDOW: usize = 1024;
+/// Cycles a window has to span to tell apart two notes a semitone apart.
+pub const SEMITONE_Q: f32 = 16.82;
+pub const LOG_LOWEST_MIDI: u32 = 28;
+pub const LOG_HIGHEST_MIDI: u32 = 124;
+pub const LOG_BINS_PER_SEMITONE: usize = 5;
+
+pub const DEFAULT_GATE_DB: f32 = -60.0;
+/// Frames this close to the quietest recent level count as silence, even above the gate.
+pub const SILENCE_MARGIN_DB: f32 = 3.0;
+/// How fast the quietest level creeps back up, in dB per frame, so it follows a room getting louder.
+pub const NOISE_LEVEL_RISE_DB: f32 = 0.01;
+/// Furthest the quietest level can creep above the quietest frame since the floor was reset,
+/// so sustained playing never becomes the silence it's judged against.
+pub const MAX_NOISE_LEVEL_RISE_DB: f32 = 6.0;
+/// Frames the quietest level is taken from before anything above the gate is learnt as silence.
+pub const NOISE_SEED_FRAMES: usize = 20;
+pub const NOISE_FLOOR_SMOOTHING: f32 = 0.05;
+pub const INITIAL_NOISE_FLOOR: f32 = 10.0;
+pub const MIN_NOISE_FLOOR: f32 = 0.1;
+
+pub const SAMPLE_CHUNK_SIZE: usize = 256;
+/// Enough chunks to hold a few seconds of input while the analysis thread catches up.
+pub const SAMPLE_RING_CHUNKS: usize = 1024;
+pub const SPECTRUM_RING_FRAMES: usize = 32;
+/// Chunks of input the monitor can fall behind by before the callback stops handing it more.
+pub const MONITOR_RING_CHUNKS: usize = 64;
+
+/// Used when the player hasn't picked a buffer size, and the device allows it.
+pub const DEFAULT_BUFFER_SIZE: u32 = 1920;
+/// Buffer sizes offered in the settings, where the device supports them.
+pub const BUFFER_SIZES: [u32; 7] = [64, 128, 256, 512, 1024, 1920, 4096];
+/// Sample rates offered in the settings, where the device supports them.
+pub const SAMPLE_RATES: [u32; 6] = [22050, 44100, 48000, 88200, 96000, 192000];
+
+/// How often the device thread checks for input devices coming and going.
+pub const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct MicPlugin;
 
 impl Plugin for MicPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(PreStartup, setup);
+        // Updated by hand rather than with `add_event`, so spectra that have been read can be reused
+        app .init_resource::<Events<MagnitudeSpectrum>>()
+            .init_resource::<SpectrumPool>()
+            .init_resource::<AnalysisConfig>()
+            .init_resource::<NoiseGate>()
+            .add_systems(PreStartup, setup)
+            .add_systems(First, recycle_spectra)
+            .add_systems(PreUpdate, (configure_analysis, forward_spectra).chain());
     }
 }
 
@@ -21,34 +72,60 @@ impl Plugin for MicPlugin {
 #[derive(Resource)]
 pub struct Mic {
     pub mir_sender: Option<Sender<MIRIntruction>>,
-    pub mir_receiver: Option<Receiver<MagnitudeSpectrum>>,
+    pub mir_receiver: Option<FrameReader<MagnitudeSpectrum>>,
+    pub overruns: Option<Arc<Overruns>>,
     pub device_receiver: Receiver<DeviceResponse>,
     pub device_sender: Sender<DeviceInstruction>,
+    pub monitor: MonitorTap,
+}
+
+/// Where the input callback copies what it records for monitoring, while it's enabled.
+#[derive(Clone)]
+pub struct MonitorTap {
+    pub enabled: Arc<AtomicBool>,
+    sender: Sender<SampleChunk>,
+    pub receiver: Receiver<SampleChunk>,
+}
+
+impl MonitorTap {
+    fn new() -> Self {
+        let (sender, receiver) = bounded(MONITOR_RING_CHUNKS);
+        MonitorTap { enabled: Arc::new(AtomicBool::new(false)), sender, receiver }
+    }
 }
 
 pub enum DeviceInstruction {
     GetDevices,
-    ConnectToDevice(Device),
-    ConnectToDefaultDevice,
+    ConnectToDevice(Device, StreamPreferences),
+    ConnectToDefaultDevice(StreamPreferences),
     DisconnectFromDevice,
+    /// Switches audio host, dropping the connected device since it belongs to the old one.
+    SelectHost(HostId),
 }
 
 impl Debug for DeviceInstruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::GetDevices => write!(f, "GetDevices"),
-            Self::ConnectToDevice(_) => f.debug_tuple("ConnectToDevice").field(&"Debug").finish(),
-            Self::ConnectToDefaultDevice => write!(f, "ConnectToDefaultDevice"),
+            Self::ConnectToDevice(_, preferences) => f.debug_tuple("ConnectToDevice").field(&"Debug").field(preferences).finish(),
+            Self::ConnectToDefaultDevice(preferences) => f.debug_tuple("ConnectToDefaultDevice").field(preferences).finish(),
             Self::DisconnectFromDevice => write!(f, "DisconnectFromDevice"),
+            Self::SelectHost(id) => f.debug_tuple("SelectHost").field(id).finish(),
         }
     }
 }
 
 pub enum DeviceResponse {
     Devices(Vec<Device>),
-    DeviceConnected(Device, Sender<MIRIntruction>, Receiver<MagnitudeSpectrum>),
+    DeviceConnected(Device, StreamInfo, Sender<MIRIntruction>, FrameReader<MagnitudeSpectrum>, Arc<Overruns>),
     DeviceFailedToConnect(MicConnectionError),
-    DeviceDisconnected,
+    DeviceDisconnected(DisconnectReason),
+    DevicesUnavailable(DevicesError),
+    StreamFailed(StreamError),
+    HostSelected(HostId),
+    HostUnavailable(HostId, HostUnavailable),
+    /// Handling an instruction panicked. The device thread carries on with the next one.
+    InstructionPanicked(String),
 }
 
 // TODO: redo this Debug
@@ -57,81 +134,415 @@ impl Debug for DeviceResponse {
         match self {
             Self::Devices(_) => f.debug_tuple("Devices").field(&"Debug").finish(),
             Self::DeviceConnected(..) => write!(f, "DeviceConnected"),
-            Self::DeviceFailedToConnect(_) => f.debug_tuple("DeviceFailedToConnect").field(&"Err").finish(),
-            Self::DeviceDisconnected => write!(f, "DeviceDisconnected"),
+            Self::DeviceFailedToConnect(e) => f.debug_tuple("DeviceFailedToConnect").field(&e.to_string()).finish(),
+            Self::DeviceDisconnected(reason) => f.debug_tuple("DeviceDisconnected").field(&reason.to_string()).finish(),
+            Self::DevicesUnavailable(e) => f.debug_tuple("DevicesUnavailable").field(e).finish(),
+            Self::StreamFailed(e) => f.debug_tuple("StreamFailed").field(e).finish(),
+            Self::HostSelected(id) => f.debug_tuple("HostSelected").field(id).finish(),
+            Self::HostUnavailable(id, e) => f.debug_tuple("HostUnavailable").field(id).field(e).finish(),
+            Self::InstructionPanicked(message) => f.debug_tuple("InstructionPanicked").field(message).finish(),
+        }
+    }
+}
+
+impl DeviceResponse {
+    /// What went wrong, if this reports a failure.
+    pub fn error(&self) -> Option<String> {
+        match self {
+            Self::DeviceFailedToConnect(e) => Some(e.to_string()),
+            Self::DevicesUnavailable(e) => Some(format!("Couldn't list input devices: {}", e)),
+            Self::StreamFailed(e) => Some(format!("Input stream failed: {}", e)),
+            Self::HostUnavailable(id, e) => Some(format!("Couldn't use the {} audio host: {}", id.name(), e)),
+            Self::InstructionPanicked(message) => Some(format!("The audio device thread hit an error: {}", message)),
+            Self::DeviceDisconnected(reason @ (DisconnectReason::Unplugged(_) | DisconnectReason::StreamDied(..))) => Some(reason.to_string()),
+            _ => None,
         }
     }
 }
 
+/// What the player would like the stream to use. Anything left out comes from the device's defaults.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StreamPreferences {
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<u32>,
+}
+
+/// What a connected stream ended up using, and what else its device offers.
+#[derive(Clone, Debug)]
+pub struct StreamInfo {
+    pub config: StreamConfig,
+    pub sample_format: SampleFormat,
+    pub supported: Vec<SupportedStreamConfigRange>,
+}
+
+impl StreamInfo {
+    /// Sample rates from [`SAMPLE_RATES`] the device can record at.
+    pub fn sample_rates(&self) -> Vec<u32> {
+        SAMPLE_RATES.into_iter()
+            .filter(|rate| self.usable().any(|range| supports_rate(range, *rate)))
+            .collect()
+    }
+
+    /// Buffer sizes from [`BUFFER_SIZES`] the device allows at the stream's sample rate, or all of them when it
+    /// doesn't say, so a picked size is used as is rather than clamped.
+    pub fn buffer_sizes(&self) -> Vec<u32> {
+        let rate = self.config.sample_rate.0;
+        BUFFER_SIZES.into_iter()
+            .filter(|size| self.usable().filter(|range| supports_rate(range, rate)).any(|range| match range.buffer_size() {
+                SupportedBufferSize::Range { min, max } => min <= size && size <= max,
+                SupportedBufferSize::Unknown => true,
+            }))
+            .collect()
+    }
+
+    /// The configurations in the sample format the stream is opened with, which are the only ones it can use.
+    fn usable(&self) -> impl Iterator<Item = &SupportedStreamConfigRange> {
+        self.supported.iter().filter(|range| range.sample_format() == self.sample_format)
+    }
+}
+
+#[inline]
+fn supports_rate(range: &SupportedStreamConfigRange, rate: u32) -> bool {
+    range.sample_format() == SampleFormat::F32 && range.min_sample_rate().0 <= rate && rate <= range.max_sample_rate().0
+}
+
+pub enum DisconnectReason {
+    /// The player asked for it.
+    Requested,
+    /// The device left the host's device list, usually by being unplugged.
+    Unplugged(String),
+    /// The stream reported its device gone.
+    StreamDied(String, StreamError),
+    /// The device thread failed while handling it.
+    Failed,
+}
+
+impl DisconnectReason {
+    /// Whether the device went away without being asked to.
+    pub fn unexpected(&self) -> bool {
+        !matches!(self, Self::Requested)
+    }
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Requested => write!(f, "Disconnected"),
+            Self::Unplugged(name) => write!(f, "{} was unplugged, it will reconnect when it's back", name),
+            Self::StreamDied(name, e) => write!(f, "Lost the stream from {} ({}), it will reconnect when it's back", name, e),
+            Self::Failed => write!(f, "Disconnected after an error"),
+        }
+    }
+}
+
+/// Counts of what the MIR pipeline threw away because the next stage fell behind.
+#[derive(Default)]
+pub struct Overruns {
+    /// Input samples the analysis thread didn't take in time.
+    pub samples: AtomicUsize,
+    /// Spectra overwritten before the game read them.
+    pub spectra: AtomicUsize,
+}
+
+impl Overruns {
+    pub fn samples(&self) -> usize {
+        self.samples.load(Ordering::Relaxed)
+    }
+
+    pub fn spectra(&self) -> usize {
+        self.spectra.load(Ordering::Relaxed)
+    }
+}
+
 pub enum MIRIntruction {
     SongStart,
+    /// Picks the song clock up at the given position, for a stream reconnected mid-song.
+    SongResume(Duration),
+    /// Hands every chunk of input on with its position on the song clock, or stops doing so.
+    Record(Option<Sender<(usize, SampleChunk)>>),
+    Configure(AnalysisConfig),
+    Gate(f32),
+    ResetNoiseFloor,
+    /// Answered once every chunk queued before it has been analysed, for anything that has to keep in step.
+    Sync(Sender<()>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowFunction {
+    Hann,
+    BlackmanHarris,
+    Gaussian,
+}
+
+impl WindowFunction {
+    pub const ALL: [WindowFunction; 3] = [WindowFunction::Hann, WindowFunction::BlackmanHarris, WindowFunction::Gaussian];
+
+    fn at(&self, x: f32, m: f32) -> f32 {
+        match self {
+            WindowFunction::Hann => hann(x, m),
+            WindowFunction::BlackmanHarris => {
+                let phase = std::f32::consts::TAU * x / m;
+                0.35875 - 0.48829 * phase.cos() + 0.14128 * (2.0 * phase).cos() - 0.01168 * (3.0 * phase).cos()
+            },
+            WindowFunction::Gaussian => {
+                let half = m / 2.0;
+                (-0.5 * ((x - half) / (GAUSSIAN_SIGMA * half)).powi(2)).exp()
+            },
+        }
+    }
+}
+
+/// How the MIR thread turns samples into spectra. Smaller windows react faster, larger ones resolve low notes better.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AnalysisConfig {
+    pub window_size: usize,
+    pub hop_size: usize,
+    pub window: WindowFunction,
+    /// The FFT is this many times longer than the window, which interpolates the spectrum between bins.
+    pub zero_padding: usize,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        AnalysisConfig {
+            window_size: DEFAULT_WINDOW_SIZE,
+            hop_size: DEFAULT_HOP_SIZE,
+            window: WindowFunction::Hann,
+            zero_padding: 1,
+        }
+    }
+}
+
+impl AnalysisConfig {
+    pub fn fft_size(&self) -> usize {
+        self.window_size * self.zero_padding
+    }
+
+    /// Window sizes of the multi-resolution bank, from the full window down to [`MIN_BAND_WINDOW`].
+    pub fn band_sizes(&self) -> impl Iterator<Item = usize> {
+        let smallest = MIN_BAND_WINDOW.min(self.window_size);
+        std::iter::successors(Some(self.window_size), move |size| (size / 2 >= smallest).then_some(size / 2))
+    }
+
+    /// Window coefficients, scaled so a sinusoid peaks at the same magnitude as under the default Hann window.
+    /// This keeps score thresholds meaningful whatever the configuration.
+    fn window_coefficients(&self, size: usize) -> Vec<f32> {
+        let window = (0..size).map(|x| self.window.at(x as f32, size as f32)).collect::<Vec<_>>();
+        let scale = DEFAULT_WINDOW_SIZE as f32 / 2.0 / window.iter().sum::<f32>();
+        window.into_iter().map(|w| w * scale).collect()
+    }
+}
+
+/// Frames quieter than this are treated as silence, and score nothing.
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct NoiseGate {
+    pub threshold_db: f32,
+}
+
+impl Default for NoiseGate {
+    fn default() -> Self {
+        NoiseGate { threshold_db: DEFAULT_GATE_DB }
+    }
 }
 
+#[derive(Event, Default)]
 pub struct MagnitudeSpectrum {
     pub data: Vec<f32>,
+    /// Log-frequency spectrum with [`LOG_BINS_PER_SEMITONE`] bins per semitone from [`LOG_LOWEST_MIDI`],
+    /// so bin centres fall on notes. Each bin is read from the shortest window that resolves a semitone there,
+    /// taken from the middle of the full window, so every bin is heard at [`MagnitudeSpectrum::centre_offset`].
+    pub log_data: Vec<f32>,
     pub progress: Duration,
     pub srate: f32,
-    pub rms: f32
+    pub rms: f32,
+    /// Largest absolute sample in the window.
+    pub peak: f32,
+    /// Typical magnitude of each log bin during silence.
+    pub noise_floor: Vec<f32>,
+    /// Whether the frame fell below the noise gate.
+    pub gated: bool,
+    pub config: AnalysisConfig,
+}
+
+impl Clone for MagnitudeSpectrum {
+    fn clone(&self) -> Self {
+        let mut spectrum = MagnitudeSpectrum::with_capacity(self.config);
+        spectrum.clone_from(self);
+        spectrum
+    }
+
+    /// Copies into the buffers already here, so recycled spectra don't allocate.
+    fn clone_from(&mut self, source: &Self) {
+        self.data.clone_from(&source.data);
+        self.log_data.clone_from(&source.log_data);
+        self.progress = source.progress;
+        self.srate = source.srate;
+        self.rms = source.rms;
+        self.peak = source.peak;
+        self.noise_floor.clone_from(&source.noise_floor);
+        self.gated = source.gated;
+        self.config = source.config;
+    }
 }
 
 impl MagnitudeSpectrum {
+    pub fn bin_width(&self) -> f32 {
+        self.srate / self.data.len() as f32
+    }
+
+    /// Seconds from the start of the window to its centre, which is when the spectrum is heard.
+    pub fn centre_offset(&self) -> f32 {
+        self.config.window_size as f32 / 2.0 / self.srate
+    }
+
     pub fn amplitude_at(&self, pitch: f32) -> f32 {
-        let continuous_bin = pitch / self.srate * WINDOW_SIZE as f32;
+        let continuous_bin = pitch / self.bin_width();
         let left_bin = (continuous_bin.floor() as usize) % self.data.len();
         let right_bin = (left_bin + 1) % self.data.len();
         let t = continuous_bin % 1.0;
         self.data[left_bin]*(1.0-t) + self.data[right_bin]*t
     }
 
+    /// An empty spectrum with room for one computed under `config`.
+    pub fn with_capacity(config: AnalysisConfig) -> Self {
+        MagnitudeSpectrum {
+            data: Vec::with_capacity(config.fft_size()),
+            log_data: Vec::with_capacity(log_bin_count()),
+            noise_floor: Vec::with_capacity(log_bin_count()),
+            config,
+            ..default()
+        }
+    }
+
+    /// Continuous index of `pitch` into [`MagnitudeSpectrum::log_data`].
+    #[inline]
+    pub fn log_bin(pitch: f32) -> f32 {
+        (pitch_to_midi(pitch) - LOG_LOWEST_MIDI as f32) * LOG_BINS_PER_SEMITONE as f32
+    }
+
+    #[inline]
+    pub fn log_bin_pitch(bin: usize) -> f32 {
+        440.0 * 2.0_f32.powf((LOG_LOWEST_MIDI as f32 + bin as f32 / LOG_BINS_PER_SEMITONE as f32 - 69.0) / 12.0)
+    }
+
+    /// Energy of the log bins either side of `pitch`, so anything within a bin's width of it counts.
+    pub fn pitch_energy(&self, pitch: f32) -> f32 {
+        bracket(&self.log_data, pitch, 0.0)
+    }
+
+    pub fn noise_at(&self, pitch: f32) -> f32 {
+        bracket(&self.noise_floor, pitch, INITIAL_NOISE_FLOOR)
+    }
+
+    /// How far `energy` stands above `noise`, in dB. Gated frames are silent, so they're never above it.
+    pub fn snr_db(&self, energy: f32, noise: f32) -> f32 {
+        if self.gated {
+            return 0.0;
+        }
+        (20.0 * (energy / noise.max(MIN_NOISE_FLOOR)).log10()).max(0.0)
+    }
+
+    pub fn pitch_snr_db(&self, pitch: f32) -> f32 {
+        self.snr_db(self.pitch_energy(pitch), self.noise_at(pitch))
+    }
+
+    /// Input level in dB relative to full scale.
+    pub fn level_db(&self) -> f32 {
+        to_db(self.rms)
+    }
+
+    pub fn peak_db(&self) -> f32 {
+        to_db(self.peak)
+    }
+
     pub fn approx_amplitude_at(&self, pitch: f32) -> f32 {
         let min_pitch = pitch / PITCH_APPROXIMATION;
         let max_pitch = pitch * PITCH_APPROXIMATION;
 
-        let left = (min_pitch / self.srate * WINDOW_SIZE as f32) as usize;
-        let right = (max_pitch / self.srate * WINDOW_SIZE as f32).ceil() as usize;
+        let left = (min_pitch / self.bin_width()) as usize;
+        let right = (max_pitch / self.bin_width()).ceil() as usize;
 
-        let max = (left..=right).into_iter()
+        (left..=right)
             .map(|i| self.data[i % self.data.len()])
             .reduce(|a, b| if a > b { a } else { b })
-            .unwrap();
-        max
+            .unwrap()
+    }
+}
+
+#[inline]
+fn bracket(log_data: &[f32], pitch: f32, default: f32) -> f32 {
+    let bin = MagnitudeSpectrum::log_bin(pitch);
+    if bin < 0.0 {
+        return default;
     }
+    let at = |i: usize| log_data.get(i).copied().unwrap_or(default);
+    at(bin.floor() as usize).max(at(bin.ceil() as usize))
+}
+
+#[inline]
+fn to_db(amplitude: f32) -> f32 {
+    20.0 * amplitude.max(1e-6).log10()
 }
 
 pub enum MicConnectionError {
     DefaultDeviceNotFound,
     ConfigError(Device, DefaultStreamConfigError),
-    BuildStreamError(Device, BuildStreamError)
+    BuildStreamError(Device, BuildStreamError),
+    PlayStreamError(Device, PlayStreamError),
+    UnsupportedSampleFormat(Device, SampleFormat),
+    UnsupportedSampleRate(Device, u32),
+}
+
+impl Display for MicConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DefaultDeviceNotFound => write!(f, "No default input device was found"),
+            Self::ConfigError(dev, e) => write!(f, "Couldn't get a configuration for {}: {}", device_name(dev), e),
+            Self::BuildStreamError(dev, e) => write!(f, "Couldn't open a stream on {}: {}", device_name(dev), e),
+            Self::PlayStreamError(dev, e) => write!(f, "Couldn't start the stream on {}: {}", device_name(dev), e),
+            Self::UnsupportedSampleFormat(dev, format) => write!(f, "{} records {:?} samples, which aren't supported yet", device_name(dev), format),
+            Self::UnsupportedSampleRate(dev, rate) => write!(f, "{} can't record at {} Hz", device_name(dev), rate),
+        }
+    }
+}
+
+#[inline]
+fn device_name(dev: &Device) -> String {
+    dev.name().unwrap_or_else(|_| "the device".to_owned())
 }
 
 fn setup(mut commands: Commands) {
     let (response_sender, response_receiver) = unbounded();
     let (instruction_sender, instruction_receiver) = unbounded();
 
+    let monitor = MonitorTap::new();
+    let thread_monitor = monitor.clone();
+
     std::thread::spawn(move || {
         
-        let host = cpal::default_host();
+        let mut host = cpal::default_host();
+        let _ = response_sender.send(DeviceResponse::HostSelected(host.id()));
 
-        let mut data = None;
+        let (stream_error_sender, stream_error_receiver) = unbounded();
+        let hooks = StreamHooks { errors: stream_error_sender, monitor: thread_monitor };
+        let poll = tick(DEVICE_POLL_INTERVAL);
+        let mut state = DeviceState::default();
 
-        while let Ok(instruction) = instruction_receiver.recv() {
-            match instruction {
-                DeviceInstruction::GetDevices => {
-                    let _ = response_sender.send(DeviceResponse::Devices(host.input_devices().unwrap().collect()));
+        loop {
+            select! {
+                recv(instruction_receiver) -> instruction => {
+                    let Ok(instruction) = instruction else { break };
+                    guarded(&response_sender, &mut state, |state| {
+                        handle_device_instruction(instruction, &mut host, &response_sender, &hooks, state)
+                    });
                 },
-                DeviceInstruction::ConnectToDevice(dev) => {
-                    try_device_disconnect(&response_sender, &mut data);
-                    try_device_connect(response_sender.clone(), &mut data, dev);
+                recv(stream_error_receiver) -> error => {
+                    let Ok((name, error)) = error else { continue };
+                    guarded(&response_sender, &mut state, |state| handle_stream_error(name, error, &response_sender, state));
                 },
-                DeviceInstruction::ConnectToDefaultDevice => {
-                    try_device_disconnect(&response_sender.clone(), &mut data);
-                    let Some(dev) = host.default_input_device() else {
-                        let _ = response_sender.send(DeviceResponse::DeviceFailedToConnect(MicConnectionError::DefaultDeviceNotFound));
-                        continue
-                    };
-                    try_device_connect(response_sender.clone(), &mut data, dev);
+                recv(poll) -> _ => {
+                    guarded(&response_sender, &mut state, |state| poll_devices(&host, &response_sender, &hooks, state));
                 },
-                DeviceInstruction::DisconnectFromDevice => try_device_disconnect(&response_sender, &mut data),
             }
         }
     });
@@ -140,149 +551,674 @@ fn setup(mut commands: Commands) {
         device_sender: instruction_sender, 
         device_receiver: response_receiver,
         mir_sender: None,
-        mir_receiver: None 
+        mir_receiver: None,
+        overruns: None,
+        monitor,
     });
 }
 
+/// What every stream the device thread opens reports back through.
+#[derive(Clone)]
+struct StreamHooks {
+    /// Lets the device thread decide whether the stream is dead or just hiccuped.
+    errors: Sender<(String, StreamError)>,
+    monitor: MonitorTap,
+}
+
+/// What the device thread keeps between instructions.
+#[derive(Default)]
+struct DeviceState {
+    /// The name, config and stream of the connected device.
+    connection: Option<(String, StreamConfig, Stream)>,
+    /// A device that went away without being asked to, to reconnect to when it comes back.
+    lost: Option<String>,
+    /// Names of the input devices last reported, to notice when they change.
+    known: Vec<String>,
+    /// What the last connection asked for, so a reconnect gets the same.
+    preferences: StreamPreferences,
+}
+
+/// Runs one piece of device handling, so a bug in a backend call doesn't take the whole device thread down with it.
+#[inline]
+fn guarded(response_sender: &Sender<DeviceResponse>, state: &mut DeviceState, f: impl FnOnce(&mut DeviceState)) {
+    if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(|| f(&mut *state))) {
+        let message = panic.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_owned());
+        let _ = response_sender.send(DeviceResponse::InstructionPanicked(message));
+        try_device_disconnect(response_sender, state, DisconnectReason::Failed);
+    }
+}
+
+#[inline]
+fn handle_device_instruction(
+    instruction: DeviceInstruction,
+    host: &mut Host,
+    response_sender: &Sender<DeviceResponse>,
+    hooks: &StreamHooks,
+    state: &mut DeviceState,
+) {
+    match instruction {
+        DeviceInstruction::GetDevices => {
+            match host.input_devices() {
+                Ok(devices) => {
+                    let devices: Vec<Device> = devices.collect();
+                    state.known = devices.iter().filter_map(|dev| dev.name().ok()).collect();
+                    let _ = response_sender.send(DeviceResponse::Devices(devices));
+                },
+                Err(e) => {
+                    let _ = response_sender.send(DeviceResponse::DevicesUnavailable(e));
+                },
+            }
+        },
+        DeviceInstruction::ConnectToDevice(dev, preferences) => {
+            state.lost = None;
+            state.preferences = preferences;
+            try_device_disconnect(response_sender, state, DisconnectReason::Requested);
+            try_device_connect(response_sender.clone(), hooks.clone(), state, dev);
+        },
+        DeviceInstruction::ConnectToDefaultDevice(preferences) => {
+            state.lost = None;
+            state.preferences = preferences;
+            try_device_disconnect(response_sender, state, DisconnectReason::Requested);
+            let Some(dev) = host.default_input_device() else {
+                let _ = response_sender.send(DeviceResponse::DeviceFailedToConnect(MicConnectionError::DefaultDeviceNotFound));
+                return
+            };
+            try_device_connect(response_sender.clone(), hooks.clone(), state, dev);
+        },
+        DeviceInstruction::DisconnectFromDevice => {
+            state.lost = None;
+            try_device_disconnect(response_sender, state, DisconnectReason::Requested);
+        },
+        DeviceInstruction::SelectHost(id) => {
+            match cpal::host_from_id(id) {
+                Ok(new_host) => {
+                    state.lost = None;
+                    try_device_disconnect(response_sender, state, DisconnectReason::Requested);
+                    *host = new_host;
+                    let _ = response_sender.send(DeviceResponse::HostSelected(id));
+                    handle_device_instruction(DeviceInstruction::GetDevices, host, response_sender, hooks, state);
+                },
+                Err(e) => {
+                    let _ = response_sender.send(DeviceResponse::HostUnavailable(id, e));
+                },
+            }
+        },
+    }
+}
+
 #[inline]
-fn try_device_disconnect(response_sender: &Sender<DeviceResponse>, data: &mut Option<(StreamConfig, Stream)>) {
-    if data.is_some() {
-        let _ = response_sender.send(DeviceResponse::DeviceDisconnected);
+fn handle_stream_error(name: String, error: StreamError, response_sender: &Sender<DeviceResponse>, state: &mut DeviceState) {
+    // A stream that's already been replaced can still report on its way out
+    if !state.connection.as_ref().is_some_and(|(connected, ..)| *connected == name) {
+        return;
     }
-    *data = None;
+
+    match error {
+        StreamError::DeviceNotAvailable => {
+            state.lost = Some(name.clone());
+            try_device_disconnect(response_sender, state, DisconnectReason::StreamDied(name, error));
+        },
+        error => {
+            let _ = response_sender.send(DeviceResponse::StreamFailed(error));
+        },
+    }
+}
+
+/// Whether a device that's in use still shows up when listing devices. ALSA leaves out `hw:` devices
+/// it can't open, including the one we're connected to, so losing that is left to [`StreamError::DeviceNotAvailable`].
+fn lists_busy_devices(host: &Host) -> bool {
+    // Compared by name, since `HostId::Alsa` only exists where ALSA does
+    host.id().name() != "ALSA"
 }
 
+/// Reports changes to the input devices, and reconnects to a lost device once it's back.
 #[inline]
-fn try_device_connect(response_sender: Sender<DeviceResponse>, data: &mut Option<(StreamConfig, Stream)>, dev: Device) {
-    let supported_conf = match dev.default_input_config() {
+fn poll_devices(host: &Host, response_sender: &Sender<DeviceResponse>, hooks: &StreamHooks, state: &mut DeviceState) {
+    // Listing failures are reported by GetDevices, polling just tries again next time
+    let Ok(devices) = host.input_devices() else { return };
+    let devices: Vec<Device> = devices.collect();
+    let names: Vec<String> = devices.iter().filter_map(|dev| dev.name().ok()).collect();
+
+    // Not every backend reports an error when its device goes, so check the list too where it can be trusted
+    if let Some((name, ..)) = &state.connection {
+        if lists_busy_devices(host) && !names.contains(name) {
+            let name = name.clone();
+            state.lost = Some(name.clone());
+            try_device_disconnect(response_sender, state, DisconnectReason::Unplugged(name));
+        }
+    }
+
+    if state.connection.is_none() {
+        let found = state.lost.as_ref().and_then(|lost| devices.iter().find(|dev| dev.name().ok().as_ref() == Some(lost)));
+        if let Some(dev) = found {
+            info!("Reconnecting to {}", device_name(dev));
+            try_device_connect(response_sender.clone(), hooks.clone(), state, dev.clone());
+        }
+    }
+
+    if names != state.known {
+        state.known = names;
+        let _ = response_sender.send(DeviceResponse::Devices(devices));
+    }
+}
+
+/// Sends the analysis configuration to the MIR thread whenever it, or the connected device, changes.
+fn configure_analysis(mic: Res<Mic>, config: Res<AnalysisConfig>, gate: Res<NoiseGate>) {
+    if !(mic.is_changed() || config.is_changed() || gate.is_changed()) {
+        return;
+    }
+    if let Some(mir_sender) = &mic.mir_sender {
+        let _ = mir_sender.send(MIRIntruction::Configure(*config));
+        let _ = mir_sender.send(MIRIntruction::Gate(gate.threshold_db));
+    }
+}
+
+/// Spectra whose events have expired, to be copied into rather than allocating a new one every frame.
+#[derive(Resource, Default)]
+struct SpectrumPool(Vec<MagnitudeSpectrum>);
+
+/// Does what `add_event` would, keeping the spectra it drops.
+fn recycle_spectra(mut spectra: ResMut<Events<MagnitudeSpectrum>>, mut pool: ResMut<SpectrumPool>) {
+    pool.0.extend(spectra.update_drain());
+}
+
+/// Hands each spectrum to every system that wants it, rather than whichever drains the channel first.
+fn forward_spectra(mic: Res<Mic>, mut spectra: EventWriter<MagnitudeSpectrum>, mut pool: ResMut<SpectrumPool>) {
+    if let Some(mir_receiver) = &mic.mir_receiver {
+        mir_receiver.read(|spectrum| {
+            let mut event = pool.0.pop().unwrap_or_else(|| MagnitudeSpectrum::with_capacity(spectrum.config));
+            event.clone_from(spectrum);
+            spectra.send(event);
+        });
+    }
+}
+
+#[inline]
+fn try_device_disconnect(response_sender: &Sender<DeviceResponse>, state: &mut DeviceState, reason: DisconnectReason) {
+    if state.connection.take().is_some() {
+        let _ = response_sender.send(DeviceResponse::DeviceDisconnected(reason));
+    }
+}
+
+#[inline]
+fn try_device_connect(response_sender: Sender<DeviceResponse>, hooks: StreamHooks, state: &mut DeviceState, dev: Device) {
+    let default_conf = match dev.default_input_config() {
         Ok(c) => c,
         Err(e) => {
             let _ = response_sender.send(DeviceResponse::DeviceFailedToConnect(MicConnectionError::ConfigError(dev, e)));
             return
         }
     };
+    let supported: Vec<SupportedStreamConfigRange> = match dev.supported_input_configs() {
+        Ok(configs) => configs.collect(),
+        Err(e) => {
+            warn!("Couldn't list configurations for {}, using its default: {}", device_name(&dev), e);
+            Vec::new()
+        }
+    };
+
+    let supported_conf = match choose_config(default_conf, &supported, state.preferences) {
+        Some(c) => c,
+        None => {
+            let rate = state.preferences.sample_rate.unwrap_or_default();
+            let _ = response_sender.send(DeviceResponse::DeviceFailedToConnect(MicConnectionError::UnsupportedSampleRate(dev, rate)));
+            return
+        }
+    };
 
+    let buffer_size = state.preferences.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
     let conf = StreamConfig {
         channels: supported_conf.channels(),
         sample_rate: supported_conf.sample_rate(),
         buffer_size: match supported_conf.buffer_size() {
-            cpal::SupportedBufferSize::Range { min, .. } => cpal::BufferSize::Fixed(*min.max(&1920)),
-            cpal::SupportedBufferSize::Unknown => cpal::BufferSize::Fixed(1920),
+            SupportedBufferSize::Range { min, max } => cpal::BufferSize::Fixed(buffer_size.clamp(*min, *max)),
+            SupportedBufferSize::Unknown => cpal::BufferSize::Fixed(buffer_size),
         }
     };
 
-    let supported_conf = supported_conf.sample_format();
+    let sample_format = supported_conf.sample_format();
+    let info = StreamInfo { config: conf.clone(), sample_format, supported };
 
-    let (stream, mir_sender, mir_receiver) = match new_stream(&dev, &conf, supported_conf) {
+    let name = device_name(&dev);
+    let (stream, mir_sender, mir_receiver, overruns) = match new_stream(&dev, &conf, sample_format, name.clone(), hooks) {
         Ok(s) => s,
         Err(e) => {
-            let _ = response_sender.send(DeviceResponse::DeviceFailedToConnect(MicConnectionError::BuildStreamError(dev, e)));
+            let _ = response_sender.send(DeviceResponse::DeviceFailedToConnect(e));
             return
         }
     };
-    stream.play().unwrap();
+    if let Err(e) = stream.play() {
+        let _ = response_sender.send(DeviceResponse::DeviceFailedToConnect(MicConnectionError::PlayStreamError(dev, e)));
+        return
+    }
 
-    *data = Some((conf, stream));
+    state.connection = Some((name, conf, stream));
+    state.lost = None;
 
-    let _ = response_sender.send(DeviceResponse::DeviceConnected(dev, mir_sender, mir_receiver));
+    let _ = response_sender.send(DeviceResponse::DeviceConnected(dev, info, mir_sender, mir_receiver, overruns));
 }
 
+/// Picks the device's default configuration unless the player asked for another sample rate,
+/// in which case it's the first supported one recording at that rate, preferring the default's channels.
 #[inline]
-fn new_stream(device: &Device, config: &StreamConfig, sample_format: SampleFormat) -> Result<(Stream, Sender<MIRIntruction>, Receiver<MagnitudeSpectrum>), BuildStreamError> {
-    let e = move |err| error!("an error occurred on stream: {}", err);
+fn choose_config(default: SupportedStreamConfig, supported: &[SupportedStreamConfigRange], preferences: StreamPreferences) -> Option<SupportedStreamConfig> {
+    let Some(rate) = preferences.sample_rate.filter(|rate| *rate != default.sample_rate().0) else {
+        return Some(default);
+    };
 
-    let (mir_instruction_sender, mir_instruction_receiver) = unbounded();
-    let (mir_response_sender, mir_response_receiver) = unbounded();
+    supported.iter()
+        .filter(|range| supports_rate(range, rate))
+        .min_by_key(|range| range.channels() != default.channels())
+        .map(|range| range.clone().with_sample_rate(SampleRate(rate)))
+}
 
-    let mut buffer: Vec<Complex<f32>> = Vec::with_capacity(WINDOW_SIZE);
-    let mut pre_buffer: VecDeque<f32> = VecDeque::with_capacity(2*WINDOW_SIZE);
+/// A fixed-size piece of input, so samples can be handed over without allocating in the audio callback.
+#[derive(Clone, Copy)]
+pub struct SampleChunk {
+    len: usize,
+    samples: [f32; SAMPLE_CHUNK_SIZE],
+    /// Samples the callback couldn't queue just before this chunk, which the song clock skips over.
+    pub dropped: usize,
+}
 
-    let hann = (0..WINDOW_SIZE).into_iter().map(|x| hann(x as f32, WINDOW_SIZE as f32)).collect::<Vec<_>>();
-    let fft = FftPlanner::<f32>::new().plan_fft_forward(WINDOW_SIZE);
+impl SampleChunk {
+    /// Copies up to [`SAMPLE_CHUNK_SIZE`] samples from the front of `samples`.
+    pub fn new(samples: &[f32]) -> Self {
+        let len = samples.len().min(SAMPLE_CHUNK_SIZE);
+        let mut chunk = SampleChunk { len, samples: [0.0; SAMPLE_CHUNK_SIZE], dropped: 0 };
+        chunk.samples[..len].copy_from_slice(&samples[..len]);
+        chunk
+    }
 
-    let mut song_start = None;
+    pub fn samples(&self) -> &[f32] {
+        &self.samples[..self.len]
+    }
+}
 
-    let srate = config.sample_rate.0 as f32;
+#[inline]
+#[allow(clippy::type_complexity)]
+fn new_stream(
+    device: &Device,
+    config: &StreamConfig,
+    sample_format: SampleFormat,
+    name: String,
+    hooks: StreamHooks,
+) -> Result<(Stream, Sender<MIRIntruction>, FrameReader<MagnitudeSpectrum>, Arc<Overruns>), MicConnectionError> {
+    let StreamHooks { errors, monitor } = hooks;
+    let e = move |err: StreamError| {
+        error!("an error occurred on stream: {}", err);
+        let _ = errors.send((name.clone(), err));
+    };
 
-    println!("{:?}", config);
-    match sample_format {
-        cpal::SampleFormat::F32 => device.build_input_stream(config, move |data: &[f32], callback_info| {
+    let AnalysisInput { chunks: chunk_sender, instructions, frames, overruns } = spawn_analysis(config.sample_rate.0 as f32);
 
-            handle_instructions(&mir_instruction_receiver, &mut song_start, &mut buffer, &mut pre_buffer, callback_info);
+    info!(channels = config.channels, sample_rate = config.sample_rate.0, buffer_size = ?config.buffer_size, ?sample_format, "Opening input stream");
+    let stream = match sample_format {
+        cpal::SampleFormat::F32 => {
+            let overruns = overruns.clone();
+            // Samples dropped since the last chunk that got through
+            let mut dropped = 0;
+            device.build_input_stream(config, move |data: &[f32], _| {
+                let monitoring = monitor.enabled.load(Ordering::Relaxed);
+                for part in data.chunks(SAMPLE_CHUNK_SIZE) {
+                    let mut chunk = SampleChunk::new(part);
+                    if monitoring {
+                        // Dropping a chunk is a click in the monitor, but that beats blocking the callback
+                        let _ = monitor.sender.try_send(chunk);
+                    }
+                    chunk.dropped = dropped;
+                    if chunk_sender.try_send(chunk).is_ok() {
+                        dropped = 0;
+                    } else {
+                        dropped += part.len();
+                        overruns.samples.fetch_add(part.len(), Ordering::Relaxed);
+                    }
+                }
+            }, e, None).map_err(|e| MicConnectionError::BuildStreamError(device.clone(), e))?
+        },
+        format => return Err(MicConnectionError::UnsupportedSampleFormat(device.clone(), format)),
+    };
 
-            pre_buffer.extend(data);
+    Ok((stream, instructions, frames, overruns))
+}
 
-            let start_progress = start_to_capture(&song_start, &callback_info).saturating_sub(Duration::from_secs_f32(pre_buffer.len() as f32 / srate));
+/// Both ends of an analysis thread: input goes in through `chunks`, and spectra come out of `frames`.
+pub struct AnalysisInput {
+    pub chunks: Sender<SampleChunk>,
+    pub instructions: Sender<MIRIntruction>,
+    pub frames: FrameReader<MagnitudeSpectrum>,
+    pub overruns: Arc<Overruns>,
+}
 
-            let mut h = 0;
-            while h + WINDOW_SIZE < pre_buffer.len() {
-                let progress = start_progress + Duration::from_secs_f32(h as f32 / srate);
-                buffer.extend(pre_buffer.iter().skip(h).take(WINDOW_SIZE).map(to_complex));
+/// Starts analysing input recorded at `srate`, whether from a device or anywhere else.
+pub fn spawn_analysis(srate: f32) -> AnalysisInput {
+    let (instructions, instruction_receiver) = unbounded();
+    let (chunks, chunk_receiver) = bounded::<SampleChunk>(SAMPLE_RING_CHUNKS);
+    let overruns = Arc::new(Overruns::default());
 
-                let rms = buffer.iter().map(|c| c.re*c.re).sum::<f32>() / WINDOW_SIZE as f32;
+    let frames = (0..SPECTRUM_RING_FRAMES).map(|_| MagnitudeSpectrum::with_capacity(AnalysisConfig::default())).collect();
+    let (frame_writer, frame_reader) = frame_ring(frames);
 
-                let fft_result = calculate_spectrogram(&fft, &hann, &mut buffer[0..WINDOW_SIZE]);
+    let thread_overruns = overruns.clone();
+    std::thread::spawn(move || analysis_thread(chunk_receiver, instruction_receiver, frame_writer, thread_overruns, srate));
 
-                let _ = mir_response_sender.send(MagnitudeSpectrum {
-                    data: fft_result, 
-                    rms,
-                    progress, 
-                    srate
-                });
-                
-                buffer.drain(..);
-                h += HOP_SIZE;
-            }
+    AnalysisInput { chunks, instructions, frames: frame_reader, overruns }
+}
 
-            pre_buffer.drain(..h);
+/// Turns input samples into spectra, off the audio callback. Runs until the stream or the game hangs up.
+fn analysis_thread(
+    chunks: Receiver<SampleChunk>,
+    instructions: Receiver<MIRIntruction>,
+    frames: FrameWriter<MagnitudeSpectrum>,
+    overruns: Arc<Overruns>,
+    srate: f32,
+) {
+    let mut analyzer = Analyzer::new(AnalysisConfig::default(), srate);
+    let mut pre_buffer: VecDeque<f32> = VecDeque::with_capacity(2*DEFAULT_WINDOW_SIZE);
+    // Samples since the song started, up to the front of the pre-buffer
+    let mut position = 0;
+    let mut recording = None;
 
-        }, e, None),
-        _ => todo!()
-    }.map(|s| (s, mir_instruction_sender, mir_response_receiver))
+    loop {
+        select! {
+            recv(instructions) -> instruction => {
+                match instruction {
+                    Ok(MIRIntruction::Sync(reply)) => {
+                        // Whatever was queued before the request is analysed before it's answered
+                        for chunk in chunks.try_iter() {
+                            analyse_chunk(chunk, &mut analyzer, &mut pre_buffer, &mut position, &recording, &frames, &overruns, srate);
+                        }
+                        let _ = reply.send(());
+                    },
+                    Ok(instruction) => handle_instruction(instruction, &chunks, &mut analyzer, &mut pre_buffer, &mut position, &mut recording, srate),
+                    Err(_) => return,
+                }
+            },
+            recv(chunks) -> chunk => {
+                let Ok(chunk) = chunk else { return };
+                analyse_chunk(chunk, &mut analyzer, &mut pre_buffer, &mut position, &recording, &frames, &overruns, srate);
+            },
+        }
+    }
 }
 
+/// Adds a chunk of input to the pre-buffer, and publishes a spectrum for every hop that completes a window.
 #[inline]
-fn handle_instructions(
-    mir_instruction_receiver: &Receiver<MIRIntruction>, 
-    song_start: &mut Option<StreamInstant>, 
-    buffer: &mut Vec<Complex<f32>>,
+#[allow(clippy::too_many_arguments)]
+fn analyse_chunk(
+    chunk: SampleChunk,
+    analyzer: &mut Analyzer,
     pre_buffer: &mut VecDeque<f32>,
-    callback_info: &InputCallbackInfo
+    position: &mut usize,
+    recording: &Option<Sender<(usize, SampleChunk)>>,
+    frames: &FrameWriter<MagnitudeSpectrum>,
+    overruns: &Overruns,
+    srate: f32,
 ) {
-    match mir_instruction_receiver.try_recv() {
-        Ok(MIRIntruction::SongStart) => {
-            *song_start = None;
-            buffer.drain(..);
-            pre_buffer.drain(..);
-        },
-        Err(_) => {},
+    // Samples went missing right before this chunk, so skip the clock over them rather than splice the gap
+    if chunk.dropped > 0 {
+        *position += pre_buffer.len() + chunk.dropped;
+        pre_buffer.clear();
+    }
+
+    if let Some(recording) = recording {
+        let _ = recording.send((*position + pre_buffer.len(), chunk));
     }
+    pre_buffer.extend(&chunk.samples[..chunk.len]);
+
+    let window_size = analyzer.config.window_size;
+    let samples = pre_buffer.make_contiguous();
+    let mut h = 0;
+    while h + window_size < samples.len() {
+        let Some(mut frame) = frames.acquire().or_else(|| {
+            overruns.spectra.fetch_add(1, Ordering::Relaxed);
+            frames.recycle_oldest()
+        }) else {
+            h += analyzer.config.hop_size;
+            continue
+        };
 
-    if *song_start == None {
-        *song_start = Some(callback_info.timestamp().capture);
+        let progress = Duration::from_secs_f32((*position + h) as f32 / srate);
+        analyzer.analyse_into(&samples[h..h + window_size], progress, srate, &mut frame);
+        frames.publish(frame);
+        h += analyzer.config.hop_size;
+    }
+
+    pre_buffer.drain(..h);
+    *position += h;
+}
+
+/// Learns what each log bin looks like when nothing is played, from frames that are quiet enough.
+#[derive(Clone)]
+struct NoiseFloor {
+    gate_db: f32,
+    /// The quietest recent level, which silence is judged against when the room is louder than the gate.
+    quietest_db: f32,
+    /// The quietest level since the floor was reset, which bounds how far `quietest_db` can creep up.
+    lowest_db: f32,
+    /// Frames seen since the floor was reset, up to [`NOISE_SEED_FRAMES`].
+    seen: usize,
+    floor: Vec<f32>,
+}
+
+impl Default for NoiseFloor {
+    fn default() -> Self {
+        NoiseFloor {
+            gate_db: DEFAULT_GATE_DB,
+            quietest_db: f32::INFINITY,
+            lowest_db: f32::INFINITY,
+            seen: 0,
+            floor: vec![INITIAL_NOISE_FLOOR; log_bin_count()],
+        }
     }
 }
 
+impl NoiseFloor {
+    /// Updates the floor from a frame, returning whether the frame is gated.
+    fn update(&mut self, log_data: &[f32], level_db: f32) -> bool {
+        let seeding = self.seen < NOISE_SEED_FRAMES;
+        self.seen = (self.seen + 1).min(NOISE_SEED_FRAMES);
+        self.lowest_db = self.lowest_db.min(level_db);
+        self.quietest_db = if seeding {
+            self.lowest_db
+        } else {
+            (self.quietest_db + NOISE_LEVEL_RISE_DB).min(self.lowest_db + MAX_NOISE_LEVEL_RISE_DB).min(level_db)
+        };
+
+        let gated = level_db < self.gate_db;
+        if gated || (!seeding && level_db < self.quietest_db + SILENCE_MARGIN_DB) {
+            for (floor, magnitude) in self.floor.iter_mut().zip(log_data) {
+                *floor = (*floor + NOISE_FLOOR_SMOOTHING * (magnitude - *floor)).max(MIN_NOISE_FLOOR);
+            }
+        }
+        gated
+    }
+}
+
+/// Length of [`MagnitudeSpectrum::log_data`].
 #[inline]
-fn calculate_spectrogram(fft: &Arc<dyn Fft<f32>>, window_func: &Vec<f32>, buffer: &mut [Complex<f32>]) -> Vec<f32> {
-    for (i, c) in buffer.iter_mut().enumerate() {
-        *c *= to_complex(&window_func[i])
+pub fn log_bin_count() -> usize {
+    (LOG_HIGHEST_MIDI - LOG_LOWEST_MIDI) as usize * LOG_BINS_PER_SEMITONE + 1
+}
+
+/// One resolution of the FFT bank.
+struct Band {
+    window: Vec<f32>,
+    fft: Arc<dyn Fft<f32>>,
+}
+
+/// Where a log-frequency bin reads its energy from: a band, and the range of its linear bins.
+/// Empty ranges are narrower than one linear bin, and interpolate at `centre` instead.
+struct LogBin {
+    band: usize,
+    centre: f32,
+    first: usize,
+    last: usize,
+}
+
+/// The windows and FFT plans for one analysis configuration.
+struct Analyzer {
+    config: AnalysisConfig,
+    bands: Vec<Band>,
+    log_bins: Vec<LogBin>,
+    noise: NoiseFloor,
+    buffer: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// The latest magnitudes of each band, kept to avoid reallocating them every hop.
+    magnitudes: Vec<Vec<f32>>,
+}
+
+impl Analyzer {
+    fn new(config: AnalysisConfig, srate: f32) -> Self {
+        let mut planner = FftPlanner::<f32>::new();
+        let sizes = config.band_sizes().collect::<Vec<_>>();
+
+        let bands = sizes.iter().map(|size| Band {
+            window: config.window_coefficients(*size),
+            fft: planner.plan_fft_forward(size * config.zero_padding),
+        }).collect::<Vec<_>>();
+        let scratch_len = bands.iter().map(|band| band.fft.get_inplace_scratch_len()).max().unwrap_or(0);
+
+        let half_bin = 2.0_f32.powf(0.5 / (12 * LOG_BINS_PER_SEMITONE) as f32);
+        let log_bins = (0..log_bin_count()).map(|i| {
+            let pitch = MagnitudeSpectrum::log_bin_pitch(i);
+
+            // The shortest window long enough, falling back to the longest we have
+            let band = sizes.iter()
+                .rposition(|size| *size as f32 >= SEMITONE_Q * srate / pitch)
+                .unwrap_or(0);
+            let bin_width = srate / (sizes[band] * config.zero_padding) as f32;
+
+            LogBin {
+                band,
+                centre: pitch / bin_width,
+                first: (pitch / half_bin / bin_width).ceil() as usize,
+                last: (pitch * half_bin / bin_width).floor() as usize,
+            }
+        }).collect();
+
+        Analyzer {
+            config,
+            log_bins,
+            noise: NoiseFloor::default(),
+            buffer: Vec::with_capacity(config.fft_size()),
+            scratch: vec![Complex { re: 0.0, im: 0.0 }; scratch_len],
+            magnitudes: sizes.iter().map(|size| Vec::with_capacity(size * config.zero_padding)).collect(),
+            bands,
+        }
+    }
+
+    /// Fills in the magnitudes of one band, from the FFT of the middle `window.len()` samples,
+    /// so every band is centred on the same instant as the full window.
+    fn transform(&mut self, band: usize, samples: &[f32]) {
+        let Band { window, fft } = &self.bands[band];
+        let start = (samples.len() - window.len()) / 2;
+        let samples = &samples[start..start + window.len()];
+
+        self.buffer.clear();
+        self.buffer.extend(samples.iter().zip(window).map(|(s, w)| to_complex(&(s * w))));
+        self.buffer.resize(window.len() * self.config.zero_padding, Complex { re: 0.0, im: 0.0 });
+        fft.process_with_scratch(&mut self.buffer, &mut self.scratch);
+
+        let magnitudes = &mut self.magnitudes[band];
+        magnitudes.clear();
+        magnitudes.extend(self.buffer.iter().map(|c| c.abs()));
+    }
+
+    /// Computes the spectrum of one window's worth of samples into `frame`, reusing its buffers.
+    fn analyse_into(&mut self, samples: &[f32], progress: Duration, srate: f32, frame: &mut MagnitudeSpectrum) {
+        let rms = (samples.iter().map(|s| s*s).sum::<f32>() / samples.len() as f32).sqrt();
+        let peak = samples.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+
+        for band in 0..self.bands.len() {
+            self.transform(band, samples);
+        }
+
+        frame.log_data.clear();
+        frame.log_data.extend(self.log_bins.iter().map(|bin| {
+            let data = &self.magnitudes[bin.band];
+            if bin.first <= bin.last {
+                data[bin.first.min(data.len() - 1)..=bin.last.min(data.len() - 1)].iter().copied().fold(0.0, f32::max)
+            } else {
+                let left = (bin.centre.floor() as usize).min(data.len() - 2);
+                let t = bin.centre - left as f32;
+                data[left] * (1.0 - t) + data[left + 1] * t
+            }
+        }));
+
+        frame.gated = self.noise.update(&frame.log_data, to_db(rms));
+        frame.noise_floor.clone_from(&self.noise.floor);
+
+        frame.data.clear();
+        frame.data.extend_from_slice(&self.magnitudes[0]);
+        frame.progress = progress;
+        frame.srate = srate;
+        frame.rms = rms;
+        frame.peak = peak;
+        frame.config = self.config;
     }
-    fft.process(buffer);
-    buffer.iter().map(|c| c.abs()).collect()
+
+    fn analyse(&mut self, samples: &[f32], progress: Duration, srate: f32) -> MagnitudeSpectrum {
+        let mut frame = MagnitudeSpectrum::with_capacity(self.config);
+        self.analyse_into(samples, progress, srate, &mut frame);
+        frame
+    }
+}
+
+/// Computes the spectrum at each hop through a recording, the same way the input stream does live.
+pub fn spectra(samples: &[f32], srate: f32, config: AnalysisConfig) -> impl Iterator<Item = MagnitudeSpectrum> + '_ {
+    let mut analyzer = Analyzer::new(config, srate);
+
+    (0..samples.len().saturating_sub(config.window_size)).step_by(config.hop_size).map(move |h| {
+        analyzer.analyse(&samples[h..h + config.window_size], Duration::from_secs_f32(h as f32 / srate), srate)
+    })
 }
 
 #[inline]
-fn hann(x: f32, m: f32) -> f32 {
-    (1.0 + (std::f32::consts::TAU*x/m + std::f32::consts::PI).cos())/2.0
+fn handle_instruction(
+    instruction: MIRIntruction,
+    chunks: &Receiver<SampleChunk>,
+    analyzer: &mut Analyzer,
+    pre_buffer: &mut VecDeque<f32>,
+    position: &mut usize,
+    recording: &mut Option<Sender<(usize, SampleChunk)>>,
+    srate: f32,
+) {
+    match instruction {
+        MIRIntruction::SongStart => {
+            // The song starts with the next input to arrive
+            for _ in chunks.try_iter() {}
+            pre_buffer.clear();
+            *position = 0;
+        },
+        MIRIntruction::SongResume(at) => {
+            for _ in chunks.try_iter() {}
+            pre_buffer.clear();
+            *position = (at.as_secs_f32() * srate) as usize;
+        },
+        MIRIntruction::Configure(config) if config != analyzer.config => {
+            // The log bins don't move with the configuration, so what was learnt of the noise still holds
+            let noise = std::mem::take(&mut analyzer.noise);
+            *analyzer = Analyzer { noise, ..Analyzer::new(config, srate) };
+            *position += pre_buffer.len();
+            pre_buffer.clear();
+        },
+        MIRIntruction::Configure(_) => {},
+        MIRIntruction::Gate(threshold_db) => analyzer.noise.gate_db = threshold_db,
+        MIRIntruction::Record(sender) => *recording = sender,
+        MIRIntruction::ResetNoiseFloor => {
+            let gate_db = analyzer.noise.gate_db;
+            analyzer.noise = NoiseFloor { gate_db, ..default() };
+        },
+        MIRIntruction::Sync(_) => unreachable!("the analysis thread answers syncs itself"),
+    }
 }
 
 #[inline]
-fn start_to_capture(
-    song_start: &Option<StreamInstant>, 
-    callback_info: &InputCallbackInfo
-) -> Duration {
-    callback_info.timestamp().capture.duration_since(&song_start.unwrap()).unwrap()
+fn hann(x: f32, m: f32) -> f32 {
+    (1.0 + (std::f32::consts::TAU*x/m + std::f32::consts::PI).cos())/2.0
 }
 
 #[inline]
@@ -0,0 +1,310 @@
+use std::{sync::{atomic::{AtomicU32, Ordering}, Arc}, time::Duration};
+
+use bevy::{audio::{AddAudioSource, Decodable, Source}, prelude::*};
+use cpal::BufferSize;
+use bevy_egui::{egui, EguiContexts};
+use crossbeam_channel::Receiver;
+
+use crate::{meter::settings_meter, mic::{Mic, SampleChunk, DEFAULT_BUFFER_SIZE, SAMPLE_CHUNK_SIZE}, settings::{AvailableDevices, SettingsPanel}, GameState};
+
+/// Chunks the monitor lets queue up beyond two input callbacks' worth, before skipping ahead to stay responsive.
+pub const MONITOR_LAG_MARGIN_CHUNKS: usize = 4;
+/// Seconds the monitor fades over when input runs dry or comes back, rather than cutting in and out.
+pub const MONITOR_FADE_TIME: f32 = 0.005;
+/// Highest drive maps to this much pre-gain into the soft clipper, which is still only a light crunch.
+pub const MAX_OVERDRIVE: f32 = 8.0;
+/// Comb and allpass delays of the reverb in samples at 44.1 kHz, from Schroeder's design.
+pub const REVERB_COMB_DELAYS: [usize; 4] = [1557, 1617, 1491, 1422];
+pub const REVERB_ALLPASS_DELAYS: [usize; 2] = [225, 556];
+pub const REVERB_FEEDBACK: f32 = 0.78;
+pub const REVERB_DAMPING: f32 = 0.3;
+pub const REVERB_ALLPASS_GAIN: f32 = 0.5;
+
+pub struct MonitorPlugin;
+
+impl Plugin for MonitorPlugin {
+    fn build(&self, app: &mut App) {
+        app .add_audio_source::<Monitor>()
+            .init_resource::<MonitorSettings>()
+            .init_resource::<MonitorControls>()
+            .add_systems(Update, update_controls.run_if(resource_changed::<MonitorSettings>))
+            .add_systems(Update, spawn_monitor.after(update_controls).run_if(resource_changed::<MonitorSettings>.or_else(resource_changed::<AvailableDevices>)))
+            .add_systems(Update, monitor_panel.after(settings_meter).before(SettingsPanel).run_if(in_state(GameState::Settings)));
+    }
+}
+
+#[derive(Resource, Clone, Copy, PartialEq)]
+pub struct MonitorSettings {
+    pub enabled: bool,
+    pub volume: f32,
+    pub gain_db: f32,
+    /// Overdrive from 0, clean, to 1.
+    pub drive: f32,
+    /// Reverb mix from 0, dry, to 1.
+    pub reverb: f32,
+}
+
+impl Default for MonitorSettings {
+    fn default() -> Self {
+        MonitorSettings {
+            enabled: false,
+            volume: 0.8,
+            gain_db: 0.0,
+            drive: 0.0,
+            reverb: 0.15,
+        }
+    }
+}
+
+/// The monitor settings, shared with the playing [`MonitorDecoder`] so they apply without restarting it.
+#[derive(Resource, Clone, Default)]
+pub struct MonitorControls(Arc<[AtomicU32; 4]>);
+
+impl MonitorControls {
+    fn store(&self, settings: &MonitorSettings) {
+        let values = [settings.volume, 10.0_f32.powf(settings.gain_db / 20.0), settings.drive, settings.reverb];
+        for (control, value) in self.0.iter().zip(values) {
+            control.store(value.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Volume, linear gain, drive and reverb mix.
+    fn load(&self) -> [f32; 4] {
+        std::array::from_fn(|i| f32::from_bits(self.0[i].load(Ordering::Relaxed)))
+    }
+}
+
+/// The live input, played back through the output with the backing track.
+#[derive(Asset, TypePath)]
+pub struct Monitor {
+    pub receiver: Receiver<SampleChunk>,
+    pub controls: MonitorControls,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Chunks each input callback delivers at once.
+    pub callback_chunks: usize,
+}
+
+#[derive(Component)]
+pub struct MonitorPlayback;
+
+pub struct MonitorDecoder {
+    receiver: Receiver<SampleChunk>,
+    controls: MonitorControls,
+    sample_rate: u32,
+    channels: u16,
+    chunk: Option<SampleChunk>,
+    index: usize,
+    settings: [f32; 4],
+    reverb: Reverb,
+    callback_chunks: usize,
+    /// Chunks read in a row with the queue over the lag limit.
+    lagging_for: usize,
+    /// Last input sample, held while fading out after input runs dry.
+    last: f32,
+    /// How far faded in the input is, from 0 to 1.
+    level: f32,
+    fade_step: f32,
+}
+
+impl Decodable for Monitor {
+    type DecoderItem = f32;
+    type Decoder = MonitorDecoder;
+
+    fn decoder(&self) -> Self::Decoder {
+        MonitorDecoder {
+            receiver: self.receiver.clone(),
+            controls: self.controls.clone(),
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            chunk: None,
+            index: 0,
+            settings: self.controls.load(),
+            reverb: Reverb::new(self.sample_rate),
+            callback_chunks: self.callback_chunks.max(1),
+            lagging_for: 0,
+            last: 0.0,
+            level: 0.0,
+            fade_step: 1.0 / (MONITOR_FADE_TIME * self.sample_rate as f32 * self.channels as f32).max(1.0),
+        }
+    }
+}
+
+impl MonitorDecoder {
+    fn next_input(&mut self) -> Option<f32> {
+        if let Some(sample) = self.chunk.as_ref().and_then(|chunk| chunk.samples().get(self.index)) {
+            self.index += 1;
+            return Some(*sample);
+        }
+
+        // Input arrives a callback at a time, so a queue that's briefly long is normal. Hearing yourself late
+        // is worse than missing a bit though, so once it's stayed long for a couple of callbacks, skip ahead.
+        let limit = 2 * self.callback_chunks + MONITOR_LAG_MARGIN_CHUNKS;
+        if self.receiver.len() > limit {
+            self.lagging_for += 1;
+        } else {
+            self.lagging_for = 0;
+        }
+        if self.lagging_for > 2 * self.callback_chunks {
+            while self.receiver.len() > self.callback_chunks {
+                let _ = self.receiver.try_recv();
+            }
+            self.lagging_for = 0;
+        }
+
+        self.chunk = self.receiver.try_recv().ok();
+        self.index = 0;
+        // Picking up changes once a chunk keeps the atomics off the per-sample path
+        self.settings = self.controls.load();
+
+        let sample = self.chunk.as_ref().and_then(|chunk| chunk.samples().first()).copied();
+        if sample.is_some() {
+            self.index = 1;
+        }
+        sample
+    }
+}
+
+impl Iterator for MonitorDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Runs dry as a fade to silence rather than ending, since more input is always on its way
+        let [volume, gain, drive, reverb] = self.settings;
+        let input = match self.next_input() {
+            Some(input) => {
+                self.level = (self.level + self.fade_step).min(1.0);
+                self.last = input;
+                input
+            },
+            None => {
+                self.level = (self.level - self.fade_step).max(0.0);
+                self.last
+            },
+        };
+
+        let mut sample = input * self.level * gain;
+        if drive > 0.0 {
+            let pre_gain = 1.0 + drive * (MAX_OVERDRIVE - 1.0);
+            sample = (sample * pre_gain).tanh() / pre_gain.tanh();
+        }
+        Some((self.reverb.process(sample, reverb) * volume).clamp(-1.0, 1.0))
+    }
+}
+
+impl Source for MonitorDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// A small Schroeder reverb: parallel damped combs into series allpasses.
+pub struct Reverb {
+    combs: Vec<(Vec<f32>, usize, f32)>,
+    allpasses: Vec<(Vec<f32>, usize)>,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: u32) -> Self {
+        let scale = |delay: usize| (delay as f32 * sample_rate as f32 / 44100.0).round().max(1.0) as usize;
+        Reverb {
+            combs: REVERB_COMB_DELAYS.iter().map(|delay| (vec![0.0; scale(*delay)], 0, 0.0)).collect(),
+            allpasses: REVERB_ALLPASS_DELAYS.iter().map(|delay| (vec![0.0; scale(*delay)], 0)).collect(),
+        }
+    }
+
+    pub fn process(&mut self, input: f32, mix: f32) -> f32 {
+        let mut wet = 0.0;
+        for (line, index, filtered) in self.combs.iter_mut() {
+            let delayed = line[*index];
+            *filtered = delayed * (1.0 - REVERB_DAMPING) + *filtered * REVERB_DAMPING;
+            line[*index] = input + *filtered * REVERB_FEEDBACK;
+            *index = (*index + 1) % line.len();
+            wet += delayed;
+        }
+        wet /= self.combs.len() as f32;
+
+        for (line, index) in self.allpasses.iter_mut() {
+            let delayed = line[*index];
+            line[*index] = wet + delayed * REVERB_ALLPASS_GAIN;
+            wet = delayed - wet * REVERB_ALLPASS_GAIN;
+            *index = (*index + 1) % line.len();
+        }
+
+        input * (1.0 - mix) + wet * mix
+    }
+}
+
+fn update_controls(settings: Res<MonitorSettings>, controls: Res<MonitorControls>) {
+    controls.store(&settings);
+}
+
+/// Restarts the monitor whenever it's switched on or off, or the stream it listens to changes.
+fn spawn_monitor(
+    mut commands: Commands,
+    mut monitors: ResMut<Assets<Monitor>>,
+    playing: Query<Entity, With<MonitorPlayback>>,
+    settings: Res<MonitorSettings>,
+    controls: Res<MonitorControls>,
+    devices: Res<AvailableDevices>,
+    mic: Res<Mic>,
+    mut last: Local<Option<(bool, Option<(u32, u16, BufferSize)>)>>,
+) {
+    let stream = devices.stream.as_ref().map(|info| (info.config.sample_rate.0, info.config.channels, info.config.buffer_size));
+    if *last == Some((settings.enabled, stream)) {
+        return;
+    }
+    *last = Some((settings.enabled, stream));
+
+    for entity in playing.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let enabled = settings.enabled && stream.is_some();
+    mic.monitor.enabled.store(enabled, Ordering::Relaxed);
+    let Some((sample_rate, channels, buffer_size)) = stream.filter(|_| enabled) else { return };
+    let frames = match buffer_size {
+        BufferSize::Fixed(frames) => frames,
+        BufferSize::Default => DEFAULT_BUFFER_SIZE,
+    };
+    let callback_chunks = (frames as usize * channels as usize).div_ceil(SAMPLE_CHUNK_SIZE);
+
+    // Whatever was left over from last time is stale
+    for _ in mic.monitor.receiver.try_iter() {}
+
+    let monitor = monitors.add(Monitor { receiver: mic.monitor.receiver.clone(), controls: controls.clone(), sample_rate, channels, callback_chunks });
+    commands.spawn((
+        AudioSourceBundle { source: monitor, settings: PlaybackSettings::DESPAWN },
+        MonitorPlayback,
+    ));
+}
+
+fn monitor_panel(mut contexts: EguiContexts, mut settings: ResMut<MonitorSettings>) {
+    egui::TopBottomPanel::bottom("monitor").show(contexts.ctx_mut(), |ui| {
+        let mut edited = *settings;
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut edited.enabled, "Monitor input").on_hover_text("Hear your guitar through the game's output, for setups without hardware monitoring");
+            ui.add_enabled_ui(edited.enabled, |ui| {
+                ui.add(egui::Slider::new(&mut edited.volume, 0.0..=1.0).text("Volume"));
+                ui.add(egui::Slider::new(&mut edited.gain_db, -20.0..=20.0).text("Gain (dB)"));
+                ui.add(egui::Slider::new(&mut edited.drive, 0.0..=1.0).text("Drive"));
+                ui.add(egui::Slider::new(&mut edited.reverb, 0.0..=1.0).text("Reverb"));
+            });
+        });
+        if edited != *settings {
+            *settings = edited;
+        }
+    });
+}
@@ -0,0 +1,362 @@
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use bevy::prelude::*;
+use bevy_egui::{egui::{self, Color32}, EguiContexts};
+use crossbeam_channel::{bounded, Receiver};
+use egui_plot::{HLine, Line, Plot, PlotBounds, PlotPoints, VLine};
+
+use crate::{
+    audio::Recording,
+    editor::ASSET_DIR,
+    game::{despawn_all, note_mic_time, tab_to_column, Miss, NoteResult, HIT_FORGIVENESS, HIT_Y_POS, NOTE_COLOR, NOTE_RADIUS, SCROLL_TIME, SPAWN_Y_POS, DESPAWN_Y_POS},
+    mic::{log_bin_count, spectra, AnalysisConfig},
+    session::{SessionError, SessionSidecar, SESSIONS_DIR},
+    spectrogram::{heat, SPECTROGRAM_RANGE_DB},
+    songs::{SongData, Tab},
+    GameState,
+};
+
+/// Seconds either side of the playhead shown in the spectrogram and score plot.
+pub const REPLAY_WINDOW: f32 = 3.0;
+/// Wider spectrograms are thinned out, to stay within what a texture can hold.
+pub const MAX_SPECTROGRAM_COLUMNS: usize = 8192;
+pub const SPECTROGRAM_HEIGHT: f32 = 200.0;
+/// Least height of the score plot, which grows to fit louder notes.
+pub const SCORE_PLOT_MAX: f64 = 50.0;
+
+pub const HIT_COLOR: Color = Color::LIME_GREEN;
+pub const MISS_COLOR: Color = Color::RED;
+pub const TIMING_MISS_COLOR: Color = Color::ORANGE;
+
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<SessionList>()
+            .add_systems(OnEnter(GameState::Settings), refresh_sessions)
+            .add_systems(OnExit(GameState::Replay), (despawn_all::<ReplayPlayback>, close_replay))
+            .add_systems(Update, (receive_spectrogram, replay_ui, advance_replay, replay_audio, draw_replay).chain().run_if(in_state(GameState::Replay)));
+    }
+}
+
+/// The recorded sessions on disk, by the path of their sidecar.
+#[derive(Resource, Default)]
+pub struct SessionList {
+    pub sessions: Vec<PathBuf>,
+    pub selected: Option<usize>,
+    pub error: String,
+}
+
+impl SessionList {
+    pub fn refresh(&mut self) {
+        self.sessions = std::fs::read_dir(SESSIONS_DIR).into_iter().flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "ron"))
+            .collect();
+        self.sessions.sort();
+        self.selected = self.selected.filter(|index| *index < self.sessions.len());
+    }
+}
+
+/// A recorded session being played back.
+#[derive(Resource)]
+pub struct Replay {
+    pub sidecar: SessionSidecar,
+    notes: Vec<ReplayNote>,
+    samples: Arc<Vec<f32>>,
+    pub duration: f32,
+    /// Seconds into the recording.
+    pub position: f32,
+    pub playing: bool,
+    /// Set when playback has to restart from the position, after a seek or play.
+    restart: bool,
+    spectrogram: Option<Spectrogram>,
+    analysis: Receiver<Spectrogram>,
+    texture: Option<egui::TextureHandle>,
+}
+
+/// A note of the chart, with when it was due in the recording and which of the sidecar's results is its.
+struct ReplayNote {
+    tab: Tab,
+    time: f32,
+    result: Option<usize>,
+}
+
+/// The log-frequency spectrum over the whole recording, as an image with time across and pitch up.
+struct Spectrogram {
+    image: egui::ColorImage,
+    /// Time of the first column, and seconds between columns.
+    start: f32,
+    column_time: f32,
+}
+
+impl Replay {
+    /// Opens the session whose sidecar is at `path`, with its recording next to it and its chart in the assets.
+    pub fn open(path: &Path) -> Result<Self, SessionError> {
+        let sidecar = SessionSidecar::load(path)?;
+        let chart = ron::de::from_str::<SongData>(&std::fs::read_to_string(format!("{}/{}", ASSET_DIR, sidecar.song))?)?;
+        let reader = hound::WavReader::open(path.with_extension("wav"))?;
+        let samples = reader.into_samples::<f32>().collect::<Result<Vec<f32>, _>>()?;
+
+        let channels = sidecar.channels.max(1) as usize;
+        let sample_rate = sidecar.sample_rate as f32;
+        let duration = samples.len() as f32 / channels as f32 / sample_rate;
+
+        let mono: Vec<f32> = samples.chunks(channels).map(|frame| frame.iter().sum::<f32>() / channels as f32).collect();
+        let (sender, analysis) = bounded(1);
+        std::thread::spawn(move || {
+            let _ = sender.send(Spectrogram::new(&mono, sample_rate));
+        });
+
+        Ok(Replay {
+            notes: replay_notes(&sidecar, &chart),
+            sidecar,
+            samples: Arc::new(samples),
+            duration,
+            position: 0.0,
+            playing: false,
+            restart: false,
+            spectrogram: None,
+            analysis,
+            texture: None,
+        })
+    }
+
+    fn seek(&mut self, position: f32) {
+        self.position = position.clamp(0.0, self.duration);
+        self.restart = true;
+    }
+
+    fn result(&self, note: &ReplayNote) -> Option<&NoteResult> {
+        note.result.map(|index| &self.sidecar.notes[index])
+    }
+}
+
+impl Spectrogram {
+    fn new(samples: &[f32], sample_rate: f32) -> Self {
+        let config = AnalysisConfig::default();
+        let frames = samples.len().saturating_sub(config.window_size).div_ceil(config.hop_size);
+        let step = frames.div_ceil(MAX_SPECTROGRAM_COLUMNS).max(1);
+        let bins = log_bin_count();
+
+        // Full spectra for a whole song don't fit in memory, so only every column's colours are kept
+        let mut image = egui::ColorImage::new([frames.div_ceil(step).max(1), bins], Color32::BLACK);
+        let mut start = 0.0;
+        // Hopping further skips the columns that aren't kept, rather than analysing them and throwing them away
+        let thinned = AnalysisConfig { hop_size: config.hop_size * step, ..config };
+        for (x, frame) in spectra(samples, sample_rate, thinned).enumerate() {
+            start = frame.centre_offset();
+            for (bin, (energy, noise)) in frame.log_data.iter().zip(&frame.noise_floor).enumerate().take(bins) {
+                image[(x, bins - 1 - bin)] = heat(frame.snr_db(*energy, *noise) / SPECTROGRAM_RANGE_DB);
+            }
+        }
+
+        Spectrogram {
+            image,
+            start,
+            column_time: config.hop_size as f32 * step as f32 / sample_rate,
+        }
+    }
+
+    /// Horizontal texture coordinate of a time in the recording.
+    fn u(&self, time: f32) -> f32 {
+        (time - self.start) / self.column_time / self.image.width() as f32
+    }
+}
+
+/// The chart's notes with when they were due in the recording, and how they were judged if they were. Both are on
+/// the recording's clock, which runs slower than the chart's at anything under full speed.
+fn replay_notes(sidecar: &SessionSidecar, chart: &SongData) -> Vec<ReplayNote> {
+    let mut paused_for = 0.0;
+    chart.notes.iter().map(|note| {
+        let result = sidecar.notes.iter().position(|result| result.note == *note);
+        let time = match result {
+            // Where its hit data were measured from
+            Some(index) => {
+                paused_for = sidecar.notes[index].paused_for;
+                sidecar.notes[index].mic_time(chart.bpm, sidecar.speed)
+            },
+            // Never judged, so taken to have been through as many pauses as the one before
+            None => note_mic_time(note, chart.bpm, sidecar.speed) + paused_for,
+        };
+        ReplayNote { tab: note.tab, time, result }
+    }).collect()
+}
+
+fn note_color(result: Option<&NoteResult>) -> Color {
+    match result {
+        Some(NoteResult { hit: true, .. }) => HIT_COLOR,
+        Some(NoteResult { miss: Some(Miss::Early | Miss::Late), .. }) => TIMING_MISS_COLOR,
+        Some(NoteResult { miss: Some(_), .. }) => MISS_COLOR,
+        // Never judged, since the song ended first
+        Some(NoteResult { miss: None, .. }) | None => NOTE_COLOR,
+    }
+}
+
+fn refresh_sessions(mut sessions: ResMut<SessionList>) {
+    sessions.refresh();
+}
+
+fn close_replay(mut commands: Commands) {
+    commands.remove_resource::<Replay>();
+}
+
+fn receive_spectrogram(mut replay: ResMut<Replay>) {
+    if replay.spectrogram.is_none() {
+        if let Ok(spectrogram) = replay.analysis.try_recv() {
+            replay.spectrogram = Some(spectrogram);
+        }
+    }
+}
+
+fn replay_ui(
+    mut contexts: EguiContexts,
+    mut replay: ResMut<Replay>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let ctx = contexts.ctx_mut();
+    let replay = &mut *replay;
+
+    egui::TopBottomPanel::bottom("replay_controls").show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            if ui.button(if replay.playing { "Pause" } else { "Play" }).clicked() {
+                replay.playing = !replay.playing;
+                let position = replay.position;
+                replay.seek(position);
+            }
+
+            let mut position = replay.position;
+            ui.spacing_mut().slider_width = 500.0;
+            let response = ui.add(egui::Slider::new(&mut position, 0.0..=replay.duration).suffix(" s").fixed_decimals(2));
+            if response.changed() {
+                replay.seek(position);
+            }
+
+            if ui.button("Main Menu").clicked() {
+                next_state.set(GameState::Settings);
+            }
+        });
+    });
+
+    if replay.texture.is_none() {
+        if let Some(spectrogram) = &replay.spectrogram {
+            replay.texture = Some(ctx.load_texture("replay_spectrogram", spectrogram.image.clone(), egui::TextureOptions::LINEAR));
+        }
+    }
+
+    egui::SidePanel::right("replay_plots").default_width(420.0).show(ctx, |ui| {
+        let (from, to) = (replay.position - REPLAY_WINDOW, replay.position + REPLAY_WINDOW);
+
+        ui.heading("Spectrogram");
+        let (rect, response) = ui.allocate_exact_size(egui::vec2(ui.available_width(), SPECTROGRAM_HEIGHT), egui::Sense::click());
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(rect, 0.0, Color32::BLACK);
+        let x = |time: f32| rect.left() + rect.width() * (time - from) / (to - from);
+        match (&replay.spectrogram, &replay.texture) {
+            (Some(spectrogram), Some(texture)) => {
+                let (start, end) = (from.max(0.0), to.min(replay.duration));
+                let uv = egui::Rect::from_min_max(egui::pos2(spectrogram.u(start), 0.0), egui::pos2(spectrogram.u(end), 1.0));
+                let shown = egui::Rect::from_min_max(egui::pos2(x(start), rect.top()), egui::pos2(x(end), rect.bottom()));
+                painter.image(texture.id(), shown, uv, Color32::WHITE);
+            },
+            _ => {
+                painter.text(rect.center(), egui::Align2::CENTER_CENTER, "Analysing...", egui::FontId::default(), Color32::GRAY);
+            },
+        }
+        painter.vline(x(replay.position), rect.y_range(), egui::Stroke::new(1.0, Color32::WHITE));
+        if let Some(pointer) = response.interact_pointer_pos().filter(|_| response.clicked() || response.dragged()) {
+            let time = from + (pointer.x - rect.left()) / rect.width() * (to - from);
+            replay.seek(time);
+        }
+
+        ui.heading("Scores");
+        let results: Vec<_> = replay.notes.iter()
+            .filter(|note| (from - 1.0..=to + 1.0).contains(&note.time))
+            .filter_map(|note| Some((note.time, replay.result(note)?)))
+            .collect();
+        let top = results.iter()
+            .flat_map(|(_, result)| result.hit_data.data.iter().map(|(_, score)| *score as f64))
+            .fold(SCORE_PLOT_MAX, f64::max);
+        let plot = Plot::new("replay_scores")
+            .height(ui.available_height() - 20.0)
+            .allow_drag(false)
+            .allow_zoom(false)
+            .allow_scroll(false)
+            .show(ui, |plot_ui| {
+                plot_ui.set_plot_bounds(PlotBounds::from_min_max([from as f64, 0.0], [to as f64, top]));
+                for (time, result) in results.iter() {
+                    let color = note_color(Some(result)).as_rgba_u8();
+                    let color = Color32::from_rgb(color[0], color[1], color[2]);
+                    let points: PlotPoints = result.hit_data.data.iter().map(|(diff, score)| [(time + diff) as f64, *score as f64]).collect();
+                    plot_ui.line(Line::new(points).color(color).name(format!("{} {:?}", result.note.fret, result.note.tab)));
+                    let window: PlotPoints = vec![[(time - HIT_FORGIVENESS) as f64, result.threshold as f64], [(time + HIT_FORGIVENESS) as f64, result.threshold as f64]].into();
+                    plot_ui.line(Line::new(window).color(color).style(egui_plot::LineStyle::dashed_loose()));
+                }
+                plot_ui.hline(HLine::new(0.0).color(Color32::DARK_GRAY));
+                plot_ui.vline(VLine::new(replay.position as f64).color(Color32::WHITE));
+            });
+        if plot.response.clicked() {
+            if let Some(pointer) = plot.response.interact_pointer_pos() {
+                replay.seek(plot.transform.value_from_position(pointer).x as f32);
+            }
+        }
+    });
+}
+
+fn advance_replay(mut replay: ResMut<Replay>, time: Res<Time>) {
+    if !replay.playing {
+        return;
+    }
+    replay.position += time.delta_seconds();
+    if replay.position >= replay.duration {
+        replay.position = replay.duration;
+        replay.playing = false;
+        replay.restart = true;
+    }
+}
+
+#[derive(Component)]
+pub struct ReplayPlayback;
+
+/// Restarts playback from the playhead after a seek, since Bevy's sinks can't seek.
+fn replay_audio(
+    mut commands: Commands,
+    mut replay: ResMut<Replay>,
+    mut recordings: ResMut<Assets<Recording>>,
+    playing: Query<Entity, With<ReplayPlayback>>,
+) {
+    if !replay.restart {
+        return;
+    }
+    replay.restart = false;
+
+    for entity in playing.iter() {
+        commands.entity(entity).despawn();
+    }
+    if !replay.playing {
+        return;
+    }
+
+    let (sample_rate, channels) = (replay.sidecar.sample_rate, replay.sidecar.channels.max(1));
+    let start = (replay.position * sample_rate as f32) as usize * channels as usize;
+    let recording = recordings.add(Recording { samples: replay.samples.clone(), sample_rate, channels, start });
+    commands.spawn((
+        AudioSourceBundle { source: recording, settings: PlaybackSettings::DESPAWN },
+        ReplayPlayback,
+    ));
+}
+
+/// Scrolls the chart past the hit line in time with the recording, coloured by how each note went.
+fn draw_replay(mut gizmos: Gizmos, replay: Res<Replay>) {
+    for tab in Tab::ALL {
+        gizmos.circle_2d(Vec2::new(tab_to_column(tab), HIT_Y_POS), NOTE_RADIUS, Color::WHITE);
+    }
+
+    for note in replay.notes.iter() {
+        let p = (replay.position - (note.time - SCROLL_TIME)) / SCROLL_TIME;
+        let y = SPAWN_Y_POS * (1.0 - p) + HIT_Y_POS * p;
+        if (SPAWN_Y_POS..=DESPAWN_Y_POS).contains(&y) {
+            gizmos.circle_2d(Vec2::new(tab_to_column(note.tab), y), NOTE_RADIUS, note_color(replay.result(note)));
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+/// Creates a ring over a fixed set of preallocated frames, for passing them from one thread to another
+/// without allocating. Frames go from the writer to the reader and back again, so there are never more than
+/// the ones given here.
+pub fn frame_ring<T>(frames: Vec<T>) -> (FrameWriter<T>, FrameReader<T>) {
+    let (free_sender, free_receiver) = bounded(frames.len());
+    let (full_sender, full_receiver) = bounded(frames.len());
+
+    for frame in frames {
+        let _ = free_sender.try_send(frame);
+    }
+
+    (
+        FrameWriter { free: free_receiver, full: full_sender, oldest: full_receiver.clone() },
+        FrameReader { full: full_receiver, free: free_sender },
+    )
+}
+
+pub struct FrameWriter<T> {
+    free: Receiver<T>,
+    full: Sender<T>,
+    oldest: Receiver<T>,
+}
+
+impl<T> FrameWriter<T> {
+    /// Takes a frame nobody is using to fill in.
+    pub fn acquire(&self) -> Option<T> {
+        self.free.try_recv().ok()
+    }
+
+    /// Takes back the oldest frame the reader hasn't got to yet, for when it has fallen behind.
+    pub fn recycle_oldest(&self) -> Option<T> {
+        self.oldest.try_recv().ok()
+    }
+
+    pub fn publish(&self, frame: T) {
+        // There's room for every frame, so this only fails once the reader is gone
+        let _ = self.full.try_send(frame);
+    }
+}
+
+pub struct FrameReader<T> {
+    full: Receiver<T>,
+    free: Sender<T>,
+}
+
+impl<T> FrameReader<T> {
+    /// Visits every published frame in order, handing each back to the writer afterwards.
+    pub fn read(&self, mut f: impl FnMut(&T)) {
+        for frame in self.full.try_iter() {
+            f(&frame);
+            let _ = self.free.try_send(frame);
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::{path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use bevy::{prelude::*, utils::thiserror::Error};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+
+use crate::{calibration::InputProfile, game::{CurrentSong, NoteHitData, NoteResult}, mic::{MIRIntruction, Mic, SampleChunk}, settings::AvailableDevices, songs::Note, GameState};
+
+pub const SESSIONS_DIR: &str = "sessions";
+
+pub struct SessionPlugin;
+
+impl Plugin for SessionPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<SessionRecorder>()
+            .add_systems(Update, start_recording.run_if(in_state(GameState::SongPlaying)))
+            .add_systems(Update, resume_recording.run_if(resource_changed::<Mic>.and_then(in_state(GameState::SongPlaying).or_else(in_state(GameState::Paused)))))
+            .add_systems(OnEnter(GameState::PostSongInfo), finish_recording)
+            .add_systems(OnEnter(GameState::Settings), finish_recording);
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Wav(#[from] hound::Error),
+
+    #[error(transparent)]
+    RonSpannedError(#[from] ron::error::SpannedError),
+
+    #[error(transparent)]
+    RonError(#[from] ron::Error),
+}
+
+/// Records what the mic heard during a song to `<stem>.wav`, next to the judged notes in `<stem>.ron`.
+#[derive(Resource, Default)]
+pub struct SessionRecorder {
+    pub enabled: bool,
+    active: Option<ActiveSession>,
+    /// What happened to the last recording.
+    pub status: String,
+}
+
+struct ActiveSession {
+    stem: PathBuf,
+    sender: Sender<(usize, SampleChunk)>,
+    sample_rate: u32,
+    channels: u16,
+}
+
+/// The notes of a recorded session and how they were judged. Each note's hit data are `(diff, score)` pairs,
+/// where `diff` is seconds from when the note was due; [`NoteResult::mic_time`] puts that on the recording's clock.
+/// Notes the song ended before judging are kept too, neither hit nor missed.
+#[derive(Serialize, Deserialize)]
+pub struct SessionSidecar {
+    pub song: String,
+    pub speed: f32,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub notes: Vec<NoteResult>,
+}
+
+impl SessionSidecar {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SessionError> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(ron::de::from_str(&text)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SessionError> {
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+}
+
+/// Writes chunks to a WAV file by their position on the song clock. Gaps from dropped input or a reconnect
+/// are filled with silence, so the file stays on the same timeline as the notes.
+fn write_wav(path: PathBuf, spec: hound::WavSpec, chunks: Receiver<(usize, SampleChunk)>) -> Result<(), SessionError> {
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    let mut written = 0;
+
+    for (position, chunk) in chunks.iter() {
+        for _ in written..position {
+            writer.write_sample(0.0_f32)?;
+        }
+        written = written.max(position);
+
+        for sample in chunk.samples().iter().skip(written - position) {
+            writer.write_sample(*sample)?;
+            written += 1;
+        }
+    }
+
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Starts recording once the song's first beat has started the mic clock.
+fn start_recording(
+    mut recorder: ResMut<SessionRecorder>,
+    song_data: Res<CurrentSong>,
+    devices: Res<AvailableDevices>,
+    mic: Res<Mic>,
+) {
+    if !recorder.enabled || recorder.active.is_some() || !song_data.mic_started() {
+        return;
+    }
+    let (Some(stream), Some(mir_sender)) = (&devices.stream, &mic.mir_sender) else { return };
+
+    if let Err(e) = std::fs::create_dir_all(SESSIONS_DIR) {
+        recorder.enabled = false;
+        recorder.status = format!("Couldn't create {}: {}", SESSIONS_DIR, e);
+        return;
+    }
+
+    let song = song_data.asset.path()
+        .and_then(|path| path.path().file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "song".to_owned());
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    let stem = Path::new(SESSIONS_DIR).join(format!("{}-{}", song, time));
+
+    let spec = hound::WavSpec {
+        channels: stream.config.channels,
+        sample_rate: stream.config.sample_rate.0,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let (sender, receiver) = unbounded();
+    let wav = stem.with_extension("wav");
+    std::thread::spawn(move || {
+        if let Err(e) = write_wav(wav.clone(), spec, receiver) {
+            error!("Failed to write {}: {}", wav.display(), e);
+        }
+    });
+
+    let _ = mir_sender.send(MIRIntruction::Record(Some(sender.clone())));
+    recorder.active = Some(ActiveSession { stem, sender, sample_rate: spec.sample_rate, channels: spec.channels });
+}
+
+/// Carries on recording from a stream reconnected mid-song.
+fn resume_recording(recorder: Res<SessionRecorder>, mic: Res<Mic>) {
+    if let (Some(active), Some(mir_sender)) = (&recorder.active, &mic.mir_sender) {
+        let _ = mir_sender.send(MIRIntruction::Record(Some(active.sender.clone())));
+    }
+}
+
+fn finish_recording(
+    mut recorder: ResMut<SessionRecorder>,
+    song_data: Option<Res<CurrentSong>>,
+    mic: Res<Mic>,
+    pending: Query<(&Note, &NoteHitData)>,
+    profile: Res<InputProfile>,
+) {
+    let Some(active) = recorder.active.take() else { return };
+
+    // The WAV is finished once the analysis thread lets go of its sender too
+    if let Some(mir_sender) = &mic.mir_sender {
+        let _ = mir_sender.send(MIRIntruction::Record(None));
+    }
+
+    let Some(song_data) = song_data else { return };
+
+    // Notes still being listened for when the song ended go in unjudged, with what was heard of them
+    let mut unjudged: Vec<NoteResult> = pending.iter().map(|(note, hit_data)| NoteResult {
+        note: note.clone(),
+        hit: false,
+        miss: None,
+        hit_data: hit_data.clone(),
+        threshold: profile.threshold(note.tab, note.fret),
+        paused_for: song_data.paused_for(),
+    }).collect();
+    unjudged.sort_by(|a, b| a.note.beat.total_cmp(&b.note.beat));
+
+    let sidecar = SessionSidecar {
+        song: song_data.asset.path().map(|path| path.to_string()).unwrap_or_default(),
+        speed: song_data.speed,
+        sample_rate: active.sample_rate,
+        channels: active.channels,
+        notes: song_data.results().iter().cloned().chain(unjudged).collect(),
+    };
+
+    let path = active.stem.with_extension("ron");
+    recorder.status = match sidecar.save(&path) {
+        Ok(()) => format!("Saved session to {}", active.stem.with_extension("wav").display()),
+        Err(e) => format!("Failed to save {}: {}", path.display(), e),
+    };
+    info!("{}", recorder.status);
+}
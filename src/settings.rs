@@ -1,25 +1,70 @@
-use bevy:: prelude::*;
+use bevy::{ecs::system::SystemParam, prelude::*};
 use bevy_egui::{egui::{self, Color32}, EguiContexts};
-use cpal::{traits::DeviceTrait, Device};
-use egui_plot::{Line, PlotPoints};
+use cpal::{traits::DeviceTrait, BufferSize, Device, HostId};
+use egui_plot::{Legend, Line, PlotPoints};
 
-use crate::{game::{calculate_score, CurrentSong, SCORE_THRESHOLD}, mic::{DeviceInstruction, DeviceResponse, MagnitudeSpectrum, Mic, WINDOW_SIZE}, GameState};
+use crate::{calibration::{Calibrator, DeviceProfiles, FretThreshold, InputProfile}, editor::{ChartEditor, MAX_FRET}, game::{calculate_score, Metronome, PlaySong, ReferenceGuide}, mic::{AnalysisConfig, DeviceInstruction, DeviceResponse, MIRIntruction, MagnitudeSpectrum, Mic, NoiseGate, StreamInfo, StreamPreferences, WindowFunction, DEFAULT_BUFFER_SIZE, WINDOW_SIZES, ZERO_PADDING_FACTORS}, replay::{Replay, SessionList}, songs::Tab, session::{SessionRecorder, SESSIONS_DIR}, transcribe::Recorder, tuner::{Tuner, Tuning}, GameState};
+
+/// Seconds a device error stays on screen.
+pub const ERROR_TOAST_DURATION: f32 = 8.0;
+/// Most device errors shown at once; older ones are dropped first.
+pub const MAX_ERROR_TOASTS: usize = 4;
 
 pub struct SettingsUiPlugin;
 
 impl Plugin for SettingsUiPlugin {
     fn build(&self, app: &mut App) {
         app .init_resource::<AvailableDevices>()
+            .init_resource::<DeviceErrors>()
             .add_systems(Startup, get_devices)
-            .add_systems(Update, (mic_response_handler, settings).chain().run_if(in_state(GameState::Settings)))
-            .add_systems(Update, loading.run_if(in_state(GameState::SongLoading)));
+            // Device failures can happen at any time, so they're handled and shown in every state
+            .add_systems(Update, (mic_response_handler, device_error_toasts).chain())
+            .add_systems(Update, settings.in_set(SettingsPanel).after(mic_response_handler).run_if(in_state(GameState::Settings)));
     }
 }
 
+/// The settings screen's egui panels, for ordering anything else drawn around them.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SettingsPanel;
+
 #[derive(Resource, Default)]
 pub struct AvailableDevices {
     pub available: Vec<Device>,
-    pub connected: Option<Device>
+    pub connected: Option<Device>,
+    pub host: Option<HostId>,
+    /// What the connected device's stream is using.
+    pub stream: Option<StreamInfo>,
+    pub preferences: StreamPreferences,
+}
+
+/// Recent failures reported by the device thread, shown as toasts.
+#[derive(Resource, Default)]
+pub struct DeviceErrors {
+    toasts: Vec<ErrorToast>,
+}
+
+struct ErrorToast {
+    message: String,
+    /// How many times in a row this was reported, so a failing stream doesn't flood the screen.
+    repeats: usize,
+    shown_at: f32,
+}
+
+impl DeviceErrors {
+    pub fn push(&mut self, message: String, now: f32) {
+        match self.toasts.last_mut() {
+            Some(last) if last.message == message => {
+                last.repeats += 1;
+                last.shown_at = now;
+            },
+            _ => {
+                self.toasts.push(ErrorToast { message, repeats: 1, shown_at: now });
+                if self.toasts.len() > MAX_ERROR_TOASTS {
+                    self.toasts.remove(0);
+                }
+            },
+        }
+    }
 }
 
 #[derive(PartialEq)]
@@ -29,36 +74,170 @@ enum SelectedSong {
     TestSong,
 }
 
+impl SelectedSong {
+    fn path(&self) -> &'static str {
+        match self {
+            SelectedSong::TestSong        => "songs/test.song",
+            SelectedSong::TwinkleTwinkle  => "songs/twinkle-twinkle.song",
+            SelectedSong::SoundOfSilence  => "songs/sound-of-silence.song",
+        }
+    }
+}
+
+/// Options for the next play of a song.
+#[derive(SystemParam)]
+pub struct PlaySettings<'w> {
+    pub metronome: ResMut<'w, Metronome>,
+    pub guide: ResMut<'w, ReferenceGuide>,
+    pub session: ResMut<'w, SessionRecorder>,
+    pub replays: ResMut<'w, SessionList>,
+    pub songs: EventWriter<'w, PlaySong>,
+}
+
+/// Everything that decides what counts as a played note.
+#[derive(SystemParam)]
+pub struct DetectionSettings<'w, 's> {
+    pub analysis: ResMut<'w, AnalysisConfig>,
+    pub gate: ResMut<'w, NoiseGate>,
+    pub profile: ResMut<'w, InputProfile>,
+    pub profiles: ResMut<'w, DeviceProfiles>,
+    pub calibrator: ResMut<'w, Calibrator>,
+    profile_status: Local<'s, String>,
+}
+
 fn get_devices(mic: Res<Mic>) {
     let _ = mic.device_sender.send(DeviceInstruction::GetDevices);
 }
 
 fn mic_response_handler(
     mut mic: ResMut<Mic>,
-    mut available_devices: ResMut<AvailableDevices>
+    mut available_devices: ResMut<AvailableDevices>,
+    mut errors: ResMut<DeviceErrors>,
+    time: Res<Time>,
+    state: Res<State<GameState>>,
+    mut next_state: ResMut<NextState<GameState>>,
 ) {
-    // TODO: handle this later
     while let Ok(response) = mic.device_receiver.try_recv() {
+        if let Some(message) = response.error() {
+            error!("{}", message);
+            errors.push(message, time.elapsed_seconds());
+        }
+
         match response {
             DeviceResponse::Devices(devices) => {
                 available_devices.available = devices;
             },
-            DeviceResponse::DeviceConnected(dev, sender, receiver) => {
+            DeviceResponse::DeviceConnected(dev, info, sender, receiver, overruns) => {
                 mic.mir_sender = Some(sender);
                 mic.mir_receiver = Some(receiver);
+                mic.overruns = Some(overruns);
                 available_devices.connected = Some(dev);
+                available_devices.stream = Some(info);
             },
-            DeviceResponse::DeviceDisconnected => {
+            DeviceResponse::DeviceDisconnected(reason) => {
                 mic.mir_sender = None;
                 mic.mir_receiver = None;
+                mic.overruns = None;
                 available_devices.connected = None;
+                available_devices.stream = None;
+
+                // Notes would only scroll past unheard until it's back
+                if reason.unexpected() && *state.get() == GameState::SongPlaying {
+                    next_state.set(GameState::Paused);
+                }
+            },
+            DeviceResponse::HostSelected(id) => {
+                available_devices.host = Some(id);
             },
-            DeviceResponse::DeviceFailedToConnect(_) => (), // error!("Failed to connect to device: {:?}", e),
+            DeviceResponse::DeviceFailedToConnect(_)
+            | DeviceResponse::HostUnavailable(..)
+            | DeviceResponse::DevicesUnavailable(_)
+            | DeviceResponse::StreamFailed(_)
+            | DeviceResponse::InstructionPanicked(_) => (),
         }
     }
+}
+
+fn device_error_toasts(
+    mut contexts: EguiContexts,
+    mut errors: ResMut<DeviceErrors>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed_seconds();
+    errors.toasts.retain(|toast| now - toast.shown_at < ERROR_TOAST_DURATION);
+    if errors.toasts.is_empty() {
+        return;
+    }
 
+    let mut dismissed = None;
+    egui::Area::new(egui::Id::new("device_error_toasts"))
+        .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-10.0, -10.0))
+        .order(egui::Order::Foreground)
+        .show(contexts.ctx_mut(), |ui| {
+            for (i, toast) in errors.toasts.iter().enumerate() {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        let message = match toast.repeats {
+                            1 => toast.message.clone(),
+                            n => format!("{} (x{})", toast.message, n),
+                        };
+                        ui.colored_label(Color32::LIGHT_RED, message);
+                        if ui.small_button("x").clicked() {
+                            dismissed = Some(i);
+                        }
+                    });
+                });
+            }
+        });
+
+    if let Some(i) = dismissed {
+        errors.toasts.remove(i);
+    }
+}
+
+/// Host, sample rate and buffer size pickers, returning the stream preferences picked.
+fn stream_settings(ui: &mut egui::Ui, devices: &AvailableDevices, mic: &Mic) -> StreamPreferences {
+    egui::ComboBox::from_label("Audio host").selected_text(devices.host.map_or("None", |id| id.name())).show_ui(ui, |ui| {
+        for id in cpal::available_hosts() {
+            if ui.selectable_label(devices.host == Some(id), id.name()).clicked() && devices.host != Some(id) {
+                let _ = mic.device_sender.send(DeviceInstruction::SelectHost(id));
+            }
+        }
+    });
+
+    let Some(stream) = devices.stream.clone() else { return devices.preferences };
+
+    let rate = stream.config.sample_rate.0;
+    match stream.config.buffer_size {
+        BufferSize::Fixed(size) => ui.label(format!(
+            "Stream: {} Hz, {} channels, {} sample buffer ({:.1} ms)",
+            rate, stream.config.channels, size, 1000.0 * size as f32 / rate as f32,
+        )),
+        BufferSize::Default => ui.label(format!("Stream: {} Hz, {} channels, default buffer", rate, stream.config.channels)),
+    };
+
+    let mut preferences = devices.preferences;
+    let default_text = |value: Option<u32>, unit: &str| value.map_or("Device default".to_owned(), |v| format!("{} {}", v, unit));
+    egui::ComboBox::from_label("Sample rate").selected_text(default_text(preferences.sample_rate, "Hz")).show_ui(ui, |ui| {
+        ui.selectable_value(&mut preferences.sample_rate, None, "Device default");
+        for rate in stream.sample_rates() {
+            ui.selectable_value(&mut preferences.sample_rate, Some(rate), format!("{} Hz", rate));
+        }
+    });
+    // Without a preference the stream asks for DEFAULT_BUFFER_SIZE rather than leaving it to the device
+    let default_buffer = format!("{} samples (default)", DEFAULT_BUFFER_SIZE);
+    egui::ComboBox::from_label("Buffer size").selected_text(preferences.buffer_size.map_or(default_buffer.clone(), |size| format!("{} samples", size))).show_ui(ui, |ui| {
+        ui.selectable_value(&mut preferences.buffer_size, None, default_buffer);
+        for size in stream.buffer_sizes() {
+            ui.selectable_value(&mut preferences.buffer_size, Some(size), format!("{} samples", size));
+        }
+    });
+
+    ui.separator();
+    preferences
 }
 
+#[allow(clippy::too_many_arguments)]
 fn settings(
     asset_server: Res<AssetServer>,
     mut commands: Commands,
@@ -68,7 +247,13 @@ fn settings(
     mut devices: ResMut<AvailableDevices>,
     mic: Res<Mic>,
     mut spectrum: Local<Option<MagnitudeSpectrum>>,
-    mut speed: Local<f32>
+    mut spectra: EventReader<MagnitudeSpectrum>,
+    mut speed: Local<f32>,
+    mut editor_error: Local<String>,
+    mut play: PlaySettings,
+    mut tuner: ResMut<Tuner>,
+    mut tuning: ResMut<Tuning>,
+    mut detection: DetectionSettings,
 ) {
     let ctx = contexts.ctx_mut();
     
@@ -76,6 +261,16 @@ fn settings(
         ui.heading("Select Mic");
         ui.separator();
 
+        // Only written back when changed, as anything watching for a new device would otherwise wake every frame
+        let preferences = stream_settings(ui, &devices, &mic);
+        if preferences != devices.preferences {
+            devices.preferences = preferences;
+            // A new sample rate or buffer size reconnects the device to apply it
+            if let Some(dev) = devices.connected.clone() {
+                let _ = mic.device_sender.send(DeviceInstruction::ConnectToDevice(dev, preferences));
+            }
+        }
+
         if let Some(connected_device) = &devices.connected {
             ui.label(format!("Connected device: {:?}", connected_device.name()));
             if ui.button("Disconnect").clicked() {
@@ -92,26 +287,141 @@ fn settings(
             };
             if ui.button(name).clicked() {
                 let device = devices.available.remove(index);
-                let _ = mic.device_sender.send(DeviceInstruction::ConnectToDevice(device));
+                let _ = mic.device_sender.send(DeviceInstruction::ConnectToDevice(device, devices.preferences));
                 let _ = mic.device_sender.send(DeviceInstruction::GetDevices);
             }
         }
 
         ui.separator();
 
-        if ui.button("Refresh").clicked() {
-            let _ = mic.device_sender.send(DeviceInstruction::GetDevices);
-        }
+        ui.horizontal(|ui| {
+            if ui.add_enabled(devices.connected.is_some(), egui::Button::new("Tuner")).clicked() {
+                tuner.open = !tuner.open;
+            }
+            if ui.add_enabled(devices.connected.is_some(), egui::Button::new("Calibrate")).on_hover_text("Strum each open string to set levels for this device").clicked() {
+                detection.calibrator.start();
+            }
+        });
 
-        if let Some(mir_receiver) = &mic.mir_receiver {
-            while let Ok(s) = mir_receiver.try_recv() {
-                *spectrum = Some(s);
+        if mic.mir_receiver.is_some() {
+            if let Some(s) = spectra.read().last() {
+                *spectrum = Some(s.clone());
             }
         }
         else {
             *spectrum = None
         }
 
+        ui.collapsing("Analysis", |ui| {
+            let mut config = *detection.analysis;
+            egui::ComboBox::from_label("Window size").selected_text(config.window_size.to_string()).show_ui(ui, |ui| {
+                for size in WINDOW_SIZES {
+                    ui.selectable_value(&mut config.window_size, size, size.to_string());
+                }
+            });
+            egui::ComboBox::from_label("Hop size").selected_text(config.hop_size.to_string()).show_ui(ui, |ui| {
+                for size in WINDOW_SIZES.into_iter().filter(|size| *size <= config.window_size) {
+                    ui.selectable_value(&mut config.hop_size, size, size.to_string());
+                }
+            });
+            config.hop_size = config.hop_size.min(config.window_size);
+            egui::ComboBox::from_label("Window").selected_text(format!("{:?}", config.window)).show_ui(ui, |ui| {
+                for window in WindowFunction::ALL {
+                    ui.selectable_value(&mut config.window, window, format!("{:?}", window));
+                }
+            });
+            egui::ComboBox::from_label("Zero padding").selected_text(format!("{}x", config.zero_padding)).show_ui(ui, |ui| {
+                for factor in ZERO_PADDING_FACTORS {
+                    ui.selectable_value(&mut config.zero_padding, factor, format!("{}x", factor));
+                }
+            });
+            if let Some(spectrum) = &*spectrum {
+                ui.label(format!("{:.0} ms window, {:.2} Hz bins", spectrum.centre_offset() * 2000.0, spectrum.bin_width()));
+            }
+            let bands = config.band_sizes().map(|size| size.to_string()).collect::<Vec<_>>();
+            ui.label(format!("Resolution bands: {}", bands.join(", ")));
+            if let Some(overruns) = &mic.overruns {
+                ui.label(format!("Dropped {} samples and {} spectra", overruns.samples(), overruns.spectra()));
+            }
+            if ui.button("Defaults").clicked() {
+                config = AnalysisConfig::default();
+            }
+            if config != *detection.analysis {
+                *detection.analysis = config;
+            }
+
+            ui.separator();
+
+            let mut gate = *detection.gate;
+            ui.add(egui::Slider::new(&mut gate.threshold_db, -90.0..=-20.0).text("Noise gate (dBFS)"));
+            if gate != *detection.gate {
+                *detection.gate = gate;
+            }
+            if let Some(spectrum) = &*spectrum {
+                let gated = if spectrum.gated { " (gated)" } else { "" };
+                ui.label(format!("Input level: {:.0} dBFS{}", spectrum.level_db(), gated));
+            }
+            if ui.add_enabled(mic.mir_sender.is_some(), egui::Button::new("Reset noise floor")).on_hover_text("Relearn the noise floor, e.g. after changing the input gain").clicked() {
+                if let Some(mir_sender) = &mic.mir_sender {
+                    let _ = mir_sender.send(MIRIntruction::ResetNoiseFloor);
+                }
+            }
+        });
+
+        ui.collapsing("Thresholds", |ui| {
+            let mut profile = detection.profile.clone();
+
+            for tab in Tab::ALL {
+                let mut threshold = profile.string_threshold(tab);
+                ui.add(egui::DragValue::new(&mut threshold).clamp_range(0.0..=60.0).speed(0.1).prefix(format!("{:?}: ", tab)).suffix(" dB"));
+                if threshold != profile.string_threshold(tab) {
+                    profile.thresholds.insert(tab, threshold);
+                }
+            }
+
+            let mut removed = None;
+            for (index, range) in profile.fret_thresholds.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_source(("fret_threshold", index)).selected_text(format!("{:?}", range.tab)).show_ui(ui, |ui| {
+                        for tab in Tab::ALL {
+                            ui.selectable_value(&mut range.tab, tab, format!("{:?}", tab));
+                        }
+                    });
+                    ui.label("frets");
+                    ui.add(egui::DragValue::new(&mut range.from).clamp_range(0..=MAX_FRET));
+                    ui.label("to");
+                    ui.add(egui::DragValue::new(&mut range.to).clamp_range(range.from..=MAX_FRET));
+                    ui.add(egui::DragValue::new(&mut range.threshold).clamp_range(0.0..=60.0).speed(0.1).suffix(" dB"));
+                    if ui.button("Remove").clicked() {
+                        removed = Some(index);
+                    }
+                });
+            }
+            if let Some(index) = removed {
+                profile.fret_thresholds.remove(index);
+            }
+            if ui.button("Add fret range").clicked() {
+                let threshold = profile.string_threshold(Tab::E2);
+                profile.fret_thresholds.push(FretThreshold { tab: Tab::E2, from: 0, to: 4, threshold });
+            }
+
+            if profile != *detection.profile {
+                *detection.profile = profile;
+            }
+
+            let name = devices.connected.as_ref().and_then(|dev| dev.name().ok());
+            if ui.add_enabled(name.is_some(), egui::Button::new("Save to device profile")).clicked() {
+                detection.profiles.profiles.insert(name.unwrap(), detection.profile.clone());
+                *detection.profile_status = match detection.profiles.save() {
+                    Ok(()) => "Saved".to_owned(),
+                    Err(e) => format!("Failed to save: {}", e),
+                };
+            }
+            if !detection.profile_status.is_empty() {
+                ui.label(&*detection.profile_status);
+            }
+        });
+
         ui.separator();
 
         ui.heading("Data");
@@ -122,31 +432,47 @@ fn settings(
 
             if let Some(spectrum) = &*spectrum {
                 let spectrogram_line: PlotPoints = spectrum.data[0..spectrum.data.len()/2].iter().enumerate().skip(1).map(|(x, y)| {
-                    let x = (x as f64 * (spectrum.srate as f64) / WINDOW_SIZE as f64).log2();
+                    let x = (x as f64 * spectrum.bin_width() as f64).log2();
                     let y = *y as f64;
                     [x, y]
                 }).collect();
                 
-                let score_line: Vec<[f64; 2]> = (0..spectrum.data.len()/2).into_iter().skip(1).map(|x| {
-                    let x = x as f64 * (spectrum.srate as f64)  / WINDOW_SIZE as f64;
-                    let y = calculate_score(x as f32, &spectrum) as f64;
-                    let x = x.log2();
-                    [x, y]
-                }).collect();   
+                let score_line: Vec<[f64; 2]> = (0..spectrum.log_data.len()).map(|bin| {
+                    let x = MagnitudeSpectrum::log_bin_pitch(bin);
+                    let y = calculate_score(x, spectrum, &detection.profile) as f64;
+                    [(x as f64).log2(), y]
+                }).collect();
 
-                let threshold_line: Vec<[f64; 2]> = vec![[score_line[0][0], SCORE_THRESHOLD as f64], [score_line.last().unwrap()[0], SCORE_THRESHOLD as f64]];
+                // Each threshold spans the register it applies to
+                let span = |tab: Tab, from: u32, to: u32, threshold: f32| {
+                    let pitch = |fret: u32| (tuning.adjust(tab.pitch() * 2.0_f32.powf(fret as f32 / 12.0)) as f64).log2();
+                    vec![[pitch(from), threshold as f64], [pitch(to), threshold as f64]]
+                };
+                let string_lines = Tab::ALL.map(|tab| {
+                    Line::new(span(tab, 0, MAX_FRET, detection.profile.string_threshold(tab))).name(format!("{:?}", tab))
+                });
+                let fret_lines = detection.profile.fret_thresholds.iter().map(|range| {
+                    Line::new(span(range.tab, range.from, range.to, range.threshold)).name(format!("{:?} frets {}-{}", range.tab, range.from, range.to))
+                }).collect::<Vec<_>>();
+
+                let noise_line: PlotPoints = spectrum.noise_floor.iter().enumerate().map(|(bin, y)| {
+                    [(MagnitudeSpectrum::log_bin_pitch(bin) as f64).log2(), *y as f64]
+                }).collect();
 
                 let spectrogram_line = Line::new(spectrogram_line).color(Color32::from_rgb(255, 0, 0));
+                let noise_line = Line::new(noise_line).color(Color32::GRAY).name("Noise floor");
                 let score_line = Line::new(score_line).color(Color32::from_rgb(0, 0, 255));
-                let threshold_line = Line::new(threshold_line).color(Color32::from_rgb(0, 255, 0));
                 
                 egui_plot::Plot::new("FFT").include_y(200.0).include_y(0.0).view_aspect(2.0).show(ui, |plot_ui| {
                     plot_ui.line(spectrogram_line);
+                    plot_ui.line(noise_line);
                 });
 
-                egui_plot::Plot::new("Score").include_y(0.0).view_aspect(2.0).show(ui, |plot_ui| {
+                egui_plot::Plot::new("Score (dB SNR)").include_y(0.0).view_aspect(2.0).legend(Legend::default()).show(ui, |plot_ui| {
                     plot_ui.line(score_line);
-                    plot_ui.line(threshold_line);
+                    for line in string_lines.into_iter().chain(fret_lines) {
+                        plot_ui.line(line);
+                    }
                 });
             }
 
@@ -170,31 +496,124 @@ fn settings(
         }
         ui.add(egui::Slider::new(&mut *speed, 0.25..=1.5).text("Speed"));
 
+        ui.separator();
+
+        ui.checkbox(&mut play.metronome.enabled, "Metronome");
+        ui.add(egui::Slider::new(&mut play.metronome.volume, 0.0..=1.0).text("Metronome volume"));
+        ui.horizontal(|ui| {
+            ui.label("Count-in:");
+            ui.selectable_value(&mut play.metronome.count_in_bars, 0, "None");
+            ui.selectable_value(&mut play.metronome.count_in_bars, 1, "1 bar");
+            ui.selectable_value(&mut play.metronome.count_in_bars, 2, "2 bars");
+        });
+
+        ui.separator();
+
+        ui.add(egui::DragValue::new(&mut tuning.reference).clamp_range(400.0..=480.0).speed(0.5).prefix("A4 = ").suffix(" Hz"));
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut tuning.offset_cents).clamp_range(-100.0..=100.0).prefix("Tuning offset: ").suffix(" cents"));
+            if ui.button("Reset").clicked() {
+                tuning.offset_cents = 0.0;
+            }
+        });
+        ui.checkbox(&mut tuning.auto_detect, "Detect tuning offset from the first notes");
+
+        ui.separator();
+
+        ui.checkbox(&mut play.guide.enabled, "Reference guide");
+        ui.add_enabled_ui(play.guide.enabled, |ui| {
+            ui.add(egui::Slider::new(&mut play.guide.volume, 0.0..=1.0).text("Guide volume"));
+            ui.checkbox(&mut play.guide.mute_while_hitting, "Mute while hitting notes");
+
+            let mut unmuted = None;
+            for (index, (from, to)) in play.guide.muted_bars.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label("Mute bars");
+                    ui.add(egui::DragValue::new(from).clamp_range(1..=999));
+                    ui.label("to");
+                    ui.add(egui::DragValue::new(to).clamp_range(*from..=999));
+                    if ui.button("Remove").clicked() {
+                        unmuted = Some(index);
+                    }
+                });
+            }
+            if let Some(index) = unmuted {
+                play.guide.muted_bars.remove(index);
+            }
+            if ui.button("Mute section").clicked() {
+                play.guide.muted_bars.push((1, 1));
+            }
+        });
+
+        ui.separator();
+
+        ui.checkbox(&mut play.session.enabled, "Record session")
+            .on_hover_text(format!("Save what the mic hears while playing, with how each note was judged, to {}/", SESSIONS_DIR));
+        if !play.session.status.is_empty() {
+            ui.colored_label(Color32::LIGHT_BLUE, &play.session.status);
+        }
+
+        ui.horizontal(|ui| {
+            let replays = &mut *play.replays;
+            let name = replays.selected.and_then(|index| replays.sessions.get(index))
+                .and_then(|path| path.file_stem())
+                .map_or_else(|| "None".to_owned(), |stem| stem.to_string_lossy().into_owned());
+            egui::ComboBox::from_label("Session")
+                .selected_text(name)
+                .show_ui(ui, |ui| {
+                    for (index, path) in replays.sessions.iter().enumerate() {
+                        let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+                        ui.selectable_value(&mut replays.selected, Some(index), stem);
+                    }
+                });
+            if ui.button("Refresh").clicked() {
+                replays.refresh();
+            }
+        });
+
+        let selected_session = play.replays.selected.and_then(|index| play.replays.sessions.get(index)).cloned();
+        if ui.add_enabled(selected_session.is_some(), egui::Button::new("Replay Session")).clicked() {
+            match Replay::open(&selected_session.unwrap()) {
+                Ok(replay) => {
+                    commands.insert_resource(replay);
+                    next_state.set(GameState::Replay);
+                    play.replays.error.clear();
+                },
+                Err(e) => play.replays.error = format!("Failed to open session: {}", e),
+            }
+        }
+        if !play.replays.error.is_empty() {
+            ui.colored_label(Color32::RED, &play.replays.error);
+        }
+
         ui.separator();
         
         if ui.add_enabled(selected_song.is_some() && devices.connected.is_some(), egui::Button::new("Play")).clicked() {
-            let song_asset = match selected_song {
-                Some(SelectedSong::TestSong)        => asset_server.load("songs/test.song"),
-                Some(SelectedSong::TwinkleTwinkle)  => asset_server.load("songs/twinkle-twinkle.song"),
-                Some(SelectedSong::SoundOfSilence)  => asset_server.load("songs/sound-of-silence.song"),
-                None => unreachable!(), 
-            };
-            commands.insert_resource(CurrentSong::new(song_asset, *speed));
-                
-            next_state.set(GameState::SongLoading);
+            play.songs.send(PlaySong { path: selected_song.as_ref().unwrap().path().to_owned(), speed: *speed });
+        }
+
+        if ui.add_enabled(selected_song.is_some(), egui::Button::new("Edit Chart")).clicked() {
+            match ChartEditor::open(selected_song.as_ref().unwrap().path(), &asset_server) {
+                Ok(editor) => {
+                    commands.insert_resource(editor);
+                    next_state.set(GameState::Editor);
+                    editor_error.clear();
+                },
+                Err(e) => *editor_error = format!("Failed to open chart: {}", e),
+            }
+        }
+
+        if ui.add_enabled(devices.connected.is_some(), egui::Button::new("Record Chart")).clicked() {
+            commands.insert_resource(Recorder::default());
+            next_state.set(GameState::Recording);
+        }
+
+        if !editor_error.is_empty() {
+            ui.colored_label(Color32::RED, &*editor_error);
         }
     });
 }
 
-fn loading(
-    mut next_state: ResMut<NextState<GameState>>,
-    asset_server: Res<AssetServer>,
-    song: Res<CurrentSong>,
-) {
-    if asset_server.is_loaded_with_dependencies(&song.asset) {
-        next_state.set(GameState::SongPlaying);
-    }
-}
 
 
 
@@ -1,5 +1,5 @@
 use bevy::{asset::{AssetLoader, AsyncReadExt}, prelude::*, utils::thiserror::Error};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 pub struct SongPlugin;
 
@@ -10,7 +10,7 @@ impl Plugin for SongPlugin {
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Tab {
     E2,
     A2,
@@ -20,16 +20,12 @@ pub enum Tab {
     E4
 }
 
-#[derive(Clone, Debug, Deserialize, Component)]
-pub struct Note {
-    pub tab: Tab,
-    pub fret: u32,
-    pub beat: f32,
-}
+impl Tab {
+    pub const ALL: [Tab; 6] = [Tab::E2, Tab::A2, Tab::D3, Tab::G3, Tab::B3, Tab::E4];
 
-impl Note {
+    /// Frequency of the open string in standard tuning.
     pub fn pitch(&self) -> f32 {
-        2.0_f32.powf(1.0/12.0*(self.fret as f32)) * match self.tab {
+        match self {
             Tab::E2 =>  82.41,
             Tab::A2 => 110.00,
             Tab::D3 => 146.83,
@@ -38,19 +34,81 @@ impl Note {
             Tab::E4 => 329.63,
         }
     }
+
+    /// MIDI note number of the open string.
+    pub fn midi(&self) -> u32 {
+        match self {
+            Tab::E2 => 40,
+            Tab::A2 => 45,
+            Tab::D3 => 50,
+            Tab::G3 => 55,
+            Tab::B3 => 59,
+            Tab::E4 => 64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Component)]
+pub struct Note {
+    pub tab: Tab,
+    pub fret: u32,
+    pub beat: f32,
+}
+
+impl Note {
+    pub fn pitch(&self) -> f32 {
+        2.0_f32.powf(1.0/12.0*(self.fret as f32)) * self.tab.pitch()
+    }
+
+    pub fn midi(&self) -> u32 {
+        self.tab.midi() + self.fret
+    }
 }
 
-#[derive(Debug, Deserialize)]
+pub const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+#[inline]
+pub fn midi_to_pitch(midi: u32) -> f32 {
+    440.0 * 2.0_f32.powf((midi as f32 - 69.0) / 12.0)
+}
+
+/// Fractional MIDI note number, so the distance to the nearest note is in hundredths of cents.
+#[inline]
+pub fn pitch_to_midi(pitch: f32) -> f32 {
+    69.0 + 12.0 * (pitch / 440.0).log2()
+}
+
+#[inline]
+pub fn midi_to_name(midi: u32) -> String {
+    format!("{}{}", NOTE_NAMES[midi as usize % 12], midi as i32 / 12 - 1)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SongData {
     pub backing: Option<String>,
     pub bpm: f32,
+    #[serde(default = "default_beats_per_bar")]
+    pub beats_per_bar: u32,
     pub notes: Vec<Note>
 }
 
+#[inline]
+fn default_beats_per_bar() -> u32 {
+    4
+}
+
+impl SongData {
+    /// Serializes with one note per line, like the hand-written charts.
+    pub fn to_ron(&self) -> Result<String, ron::Error> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default().depth_limit(2))
+    }
+}
+
 #[derive(Asset, TypePath, Debug)]
 pub struct Song {
     pub backing: Option<Handle<AudioSource>>,
     pub bpm: f32,
+    pub beats_per_bar: u32,
     pub notes: Vec<Note>,
 }
 
@@ -91,6 +149,7 @@ impl AssetLoader for SongLoader {
             let song = Song {
                 backing,
                 bpm: song_data.bpm,
+                beats_per_bar: song_data.beats_per_bar,
                 notes: song_data.notes
             };
 
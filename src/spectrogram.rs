@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_egui::{egui::{self, Color32, Stroke}, EguiContexts};
+
+use crate::{
+    game::{note_mic_time, CurrentSong, NOTE_COLOR},
+    mic::{MagnitudeSpectrum, Mic, LOG_BINS_PER_SEMITONE, LOG_LOWEST_MIDI},
+    songs::{midi_to_name, midi_to_pitch, pitch_to_midi, Song, NOTE_NAMES},
+    tuner::Tuning,
+    GameState,
+};
+
+/// Seconds of past input kept on screen.
+pub const SPECTROGRAM_HISTORY: f32 = 5.0;
+/// Seconds of upcoming notes shown ahead of the input while playing.
+pub const SPECTROGRAM_LOOKAHEAD: f32 = 2.0;
+/// Highest note shown, which is above anything on a guitar's 24th fret.
+pub const SPECTROGRAM_HIGHEST_MIDI: u32 = 90;
+/// SNR in dB drawn at full brightness.
+pub const SPECTROGRAM_RANGE_DB: f32 = 40.0;
+pub const SPECTROGRAM_HEIGHT: f32 = 240.0;
+pub const CHROMAGRAM_HEIGHT: f32 = 120.0;
+/// Width of the axis labels down the left of each view.
+pub const AXIS_WIDTH: f32 = 36.0;
+/// Seconds a chart note is drawn as lasting, since charts don't give lengths.
+pub const NOTE_MARK_LENGTH: f32 = 0.15;
+pub const OVERLAY_KEY: KeyCode = KeyCode::F2;
+
+pub struct SpectrogramPlugin;
+
+impl Plugin for SpectrogramPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<SpectrumHistory>()
+            .init_resource::<SpectrogramSettings>()
+            .add_systems(Update, update_history)
+            .add_systems(Update, settings_window.after(update_history).run_if(in_state(GameState::Settings)))
+            .add_systems(Update, (toggle_overlay, play_overlay).chain().after(update_history).run_if(in_state(GameState::SongPlaying).or_else(in_state(GameState::Paused))));
+    }
+}
+
+#[derive(Resource)]
+pub struct SpectrogramSettings {
+    pub spectrogram: bool,
+    pub chromagram: bool,
+    /// Label pitches by note name rather than frequency.
+    pub note_names: bool,
+    /// Show the views over the highway while playing.
+    pub overlay: bool,
+}
+
+impl Default for SpectrogramSettings {
+    fn default() -> Self {
+        SpectrogramSettings {
+            spectrogram: true,
+            chromagram: true,
+            note_names: true,
+            overlay: false,
+        }
+    }
+}
+
+/// Recent input, one column per spectrum, oldest first.
+#[derive(Resource, Default)]
+pub struct SpectrumHistory {
+    columns: VecDeque<Column>,
+}
+
+struct Column {
+    /// Start of the spectrum's window on the mic clock, which is what the detector times notes by.
+    time: f32,
+    /// SNR in dB of each log bin up to [`SPECTROGRAM_HIGHEST_MIDI`].
+    bins: Vec<f32>,
+    /// Strongest SNR in dB of each pitch class, from C.
+    chroma: [f32; 12],
+}
+
+impl Column {
+    fn new(spectrum: &MagnitudeSpectrum) -> Self {
+        let bins: Vec<f32> = spectrum.log_data.iter().zip(&spectrum.noise_floor)
+            .take(spectrogram_rows())
+            .map(|(energy, noise)| spectrum.snr_db(*energy, *noise))
+            .collect();
+
+        let mut chroma = [0.0_f32; 12];
+        for (bin, snr) in bins.iter().enumerate() {
+            let midi = LOG_LOWEST_MIDI as usize + (bin + LOG_BINS_PER_SEMITONE / 2) / LOG_BINS_PER_SEMITONE;
+            chroma[midi % 12] = chroma[midi % 12].max(*snr);
+        }
+
+        Column {
+            time: spectrum.progress.as_secs_f32(),
+            bins,
+            chroma,
+        }
+    }
+}
+
+impl SpectrumHistory {
+    pub fn latest_time(&self) -> Option<f32> {
+        self.columns.back().map(|column| column.time)
+    }
+
+    fn spectrogram_image(&self) -> egui::ColorImage {
+        let rows = spectrogram_rows();
+        let mut image = egui::ColorImage::new([self.columns.len().max(1), rows], Color32::BLACK);
+        for (x, column) in self.columns.iter().enumerate() {
+            for (bin, snr) in column.bins.iter().enumerate() {
+                image[(x, rows - 1 - bin)] = heat(snr / SPECTROGRAM_RANGE_DB);
+            }
+        }
+        image
+    }
+
+    fn chromagram_image(&self) -> egui::ColorImage {
+        let mut image = egui::ColorImage::new([self.columns.len().max(1), 12], Color32::BLACK);
+        for (x, column) in self.columns.iter().enumerate() {
+            for (class, snr) in column.chroma.iter().enumerate() {
+                image[(x, 11 - class)] = heat(snr / SPECTROGRAM_RANGE_DB);
+            }
+        }
+        image
+    }
+}
+
+/// Rows of the spectrogram, one per log bin.
+#[inline]
+fn spectrogram_rows() -> usize {
+    (SPECTROGRAM_HIGHEST_MIDI - LOG_LOWEST_MIDI) as usize * LOG_BINS_PER_SEMITONE + 1
+}
+
+/// Black through blue and red to yellow, for `value` from 0 to 1.
+pub fn heat(value: f32) -> Color32 {
+    let v = value.clamp(0.0, 1.0);
+    let channel = |from: f32, to: f32| (((v - from) / (to - from)).clamp(0.0, 1.0) * 255.0) as u8;
+    Color32::from_rgb(channel(0.33, 0.66), channel(0.66, 1.0), channel(0.0, 0.33).min(255 - channel(0.5, 0.9)))
+}
+
+fn update_history(
+    mic: Res<Mic>,
+    mut spectra: EventReader<MagnitudeSpectrum>,
+    mut history: ResMut<SpectrumHistory>,
+) {
+    if mic.mir_receiver.is_none() {
+        spectra.clear();
+        history.columns.clear();
+        return;
+    }
+
+    for spectrum in spectra.read() {
+        let column = Column::new(spectrum);
+        // The mic clock restarts with each song, which leaves everything before it on another timeline
+        if history.latest_time().is_some_and(|latest| column.time < latest) {
+            history.columns.clear();
+        }
+        history.columns.push_back(column);
+    }
+
+    let Some(latest) = history.latest_time() else { return };
+    while history.columns.front().is_some_and(|column| column.time < latest - SPECTROGRAM_HISTORY) {
+        history.columns.pop_front();
+    }
+}
+
+/// The views' textures. Their handles are kept between frames, but each image is rebuilt from the history
+/// and uploaded whole every frame, which is cheap at the few hundred columns at most that the history holds.
+#[derive(Default)]
+struct Textures {
+    spectrogram: Option<egui::TextureHandle>,
+    chromagram: Option<egui::TextureHandle>,
+}
+
+impl Textures {
+    fn update(ctx: &egui::Context, texture: &mut Option<egui::TextureHandle>, name: &str, image: egui::ColorImage) -> egui::TextureId {
+        match texture {
+            Some(texture) => texture.set(image, egui::TextureOptions::LINEAR),
+            None => *texture = Some(ctx.load_texture(name, image, egui::TextureOptions::LINEAR)),
+        }
+        texture.as_ref().unwrap().id()
+    }
+}
+
+/// A chart note to mark, as when it's due on the mic clock and the pitch expected.
+struct ExpectedNote {
+    time: f32,
+    pitch: f32,
+}
+
+/// Draws the enabled views, scrolling from [`SPECTROGRAM_HISTORY`] ago up to `now`, and `lookahead` seconds past it.
+fn views(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    history: &SpectrumHistory,
+    settings: &SpectrogramSettings,
+    textures: &mut Textures,
+    notes: &[ExpectedNote],
+    lookahead: f32,
+) {
+    let Some(now) = history.latest_time() else {
+        ui.label("No input");
+        return;
+    };
+    let from = now - SPECTROGRAM_HISTORY;
+    let to = now + lookahead;
+    let start = history.columns.front().map_or(now, |column| column.time);
+
+    if settings.spectrogram {
+        let texture = Textures::update(ctx, &mut textures.spectrogram, "spectrogram", history.spectrogram_image());
+        let lowest = midi_to_pitch(LOG_LOWEST_MIDI);
+        let rows = spectrogram_rows() as f32;
+        // Row positions come from log bins, so pitches land where the detector hears them
+        let row = |pitch: f32| MagnitudeSpectrum::log_bin(pitch) / (rows - 1.0);
+        view(ui, SPECTROGRAM_HEIGHT, texture, (from, to, start, now), |painter, rect, x| {
+            let y = |pitch: f32| rect.bottom() - rect.height() * row(pitch);
+            for midi in LOG_LOWEST_MIDI..=SPECTROGRAM_HIGHEST_MIDI {
+                let natural = !NOTE_NAMES[midi as usize % 12].contains('#');
+                let label = match (settings.note_names, midi % 12) {
+                    (true, _) if natural && rect.height() / rows * LOG_BINS_PER_SEMITONE as f32 >= 10.0 => midi_to_name(midi),
+                    (_, 0) if settings.note_names => midi_to_name(midi),
+                    (_, 0) => format!("{:.0}", midi_to_pitch(midi)),
+                    _ => continue,
+                };
+                axis_label(painter, rect, y(midi_to_pitch(midi)), label);
+            }
+            let semitone = rect.height() / rows * LOG_BINS_PER_SEMITONE as f32;
+            for note in notes.iter().filter(|note| note.pitch >= lowest) {
+                mark(painter, x(note.time), x(note.time + NOTE_MARK_LENGTH), y(note.pitch), semitone);
+            }
+        });
+    }
+
+    if settings.chromagram {
+        let texture = Textures::update(ctx, &mut textures.chromagram, "chromagram", history.chromagram_image());
+        view(ui, CHROMAGRAM_HEIGHT, texture, (from, to, start, now), |painter, rect, x| {
+            let row = rect.height() / 12.0;
+            let y = |class: usize| rect.bottom() - row * (class as f32 + 0.5);
+            for (class, name) in NOTE_NAMES.iter().enumerate() {
+                let label = if settings.note_names { name.to_string() } else { class.to_string() };
+                axis_label(painter, rect, y(class), label);
+            }
+            for note in notes {
+                let class = pitch_to_midi(note.pitch).round().max(0.0) as usize % 12;
+                mark(painter, x(note.time), x(note.time + NOTE_MARK_LENGTH), y(class), row);
+            }
+        });
+    }
+}
+
+/// One scrolling view: the texture across the times it covers, a line at `now`, and whatever `overlay` draws on top.
+fn view(
+    ui: &mut egui::Ui,
+    height: f32,
+    texture: egui::TextureId,
+    (from, to, start, now): (f32, f32, f32, f32),
+    overlay: impl FnOnce(&egui::Painter, egui::Rect, &dyn Fn(f32) -> f32),
+) {
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(ui.available_width(), height), egui::Sense::hover());
+    let painter = ui.painter_at(rect).with_clip_rect(rect);
+    let plot = egui::Rect::from_min_max(egui::pos2(rect.left() + AXIS_WIDTH, rect.top()), rect.max);
+    let x = |time: f32| plot.left() + plot.width() * (time - from) / (to - from);
+
+    painter.rect_filled(plot, 0.0, Color32::BLACK);
+    let shown = egui::Rect::from_min_max(egui::pos2(x(start), plot.top()), egui::pos2(x(now), plot.bottom()));
+    painter.image(texture, shown, egui::Rect::from_min_max(egui::Pos2::ZERO, egui::pos2(1.0, 1.0)), Color32::WHITE);
+    painter.vline(x(now), plot.y_range(), Stroke::new(1.0, Color32::WHITE));
+    overlay(&painter, plot, &x);
+}
+
+fn axis_label(painter: &egui::Painter, plot: egui::Rect, y: f32, label: String) {
+    painter.text(egui::pos2(plot.left() - 4.0, y), egui::Align2::RIGHT_CENTER, label, egui::FontId::monospace(10.0), Color32::GRAY);
+    painter.hline(plot.left()..=plot.left() + 4.0, y, Stroke::new(1.0, Color32::GRAY));
+}
+
+/// Outlines where a chart note should show up.
+fn mark(painter: &egui::Painter, from: f32, to: f32, y: f32, height: f32) {
+    let [r, g, b, _] = NOTE_COLOR.as_rgba_u8();
+    let rect = egui::Rect::from_min_max(egui::pos2(from, y - height / 2.0), egui::pos2(to, y + height / 2.0));
+    painter.rect_stroke(rect, 2.0, Stroke::new(1.5, Color32::from_rgb(r, g, b)));
+}
+
+fn settings_controls(ui: &mut egui::Ui, settings: &mut SpectrogramSettings) {
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut settings.spectrogram, "Spectrogram");
+        ui.checkbox(&mut settings.chromagram, "Chromagram");
+        ui.checkbox(&mut settings.note_names, "Note names");
+        ui.checkbox(&mut settings.overlay, "Show while playing")
+            .on_hover_text(format!("Toggle with {:?} during a song", OVERLAY_KEY));
+    });
+}
+
+fn settings_window(
+    mut contexts: EguiContexts,
+    history: Res<SpectrumHistory>,
+    mut settings: ResMut<SpectrogramSettings>,
+    mut textures: Local<Textures>,
+) {
+    let ctx = contexts.ctx_mut().clone();
+    egui::Window::new("Spectrogram").default_open(false).default_width(500.0).show(&ctx, |ui| {
+        settings_controls(ui, &mut settings);
+        views(ui, &ctx, &history, &settings, &mut textures, &[], 0.0);
+    });
+}
+
+fn toggle_overlay(keys: Res<ButtonInput<KeyCode>>, mut settings: ResMut<SpectrogramSettings>) {
+    if keys.just_pressed(OVERLAY_KEY) {
+        settings.overlay = !settings.overlay;
+    }
+}
+
+/// The views over the highway, with the chart's notes ahead of the input.
+fn play_overlay(
+    mut contexts: EguiContexts,
+    history: Res<SpectrumHistory>,
+    settings: Res<SpectrogramSettings>,
+    song_data: Res<CurrentSong>,
+    songs: Res<Assets<Song>>,
+    tuning: Res<Tuning>,
+    mut textures: Local<Textures>,
+) {
+    if !settings.overlay {
+        return;
+    }
+    let (Some(song), Some(now)) = (songs.get(&song_data.asset), history.latest_time()) else { return };
+
+    let from = now - SPECTROGRAM_HISTORY;
+    let to = now + SPECTROGRAM_LOOKAHEAD;
+    let notes: Vec<ExpectedNote> = song.notes.iter()
+        .map(|note| ExpectedNote {
+            time: note_mic_time(note, song.bpm, song_data.speed) + song_data.paused_for(),
+            pitch: tuning.adjust(note.pitch()),
+        })
+        .filter(|note| note.time + NOTE_MARK_LENGTH >= from && note.time <= to)
+        .collect();
+
+    let ctx = contexts.ctx_mut().clone();
+    egui::Window::new("Input")
+        .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-10.0, 10.0))
+        .default_width(420.0)
+        .frame(egui::Frame::window(&ctx.style()).multiply_with_opacity(0.85))
+        .show(&ctx, |ui| {
+            views(ui, &ctx, &history, &settings, &mut textures, &notes, SPECTROGRAM_LOOKAHEAD);
+        });
+}
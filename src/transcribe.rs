@@ -0,0 +1,458 @@
+use bevy::{prelude::*, time::Stopwatch};
+use bevy_egui::{egui::{self, Color32}, EguiContexts};
+
+use crate::{audio::MetronomeClicks, editor::{ChartEditor, ChartEditorError, ASSET_DIR, GRID_DIVISIONS, MAX_FRET}, game::SCORE_THRESHOLD, mic::{MIRIntruction, MagnitudeSpectrum, Mic}, songs::{midi_to_pitch, Note, SongData, Tab}, GameState};
+
+pub const LOWEST_MIDI: u32 = 40;
+pub const HIGHEST_MIDI: u32 = 88;
+pub const HARMONICS: usize = 5;
+pub const HARMONIC_DECAY: f32 = 0.8;
+
+pub const ONSET_WINDOW: usize = 8;
+pub const ONSET_DELTA: f32 = 0.1;
+pub const MIN_ONSET_GAP: f32 = 0.1;
+
+pub const MIN_BPM: f32 = 60.0;
+pub const MAX_BPM: f32 = 200.0;
+pub const PREFERRED_BPM: f32 = 120.0;
+
+pub const RECORDING_BEATS_PER_BAR: u32 = 4;
+pub const METRONOME_VOLUME: f32 = 0.5;
+
+pub struct TranscribePlugin;
+
+impl Plugin for TranscribePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (recorder_ui, recorder_clock).chain().run_if(in_state(GameState::Recording)));
+    }
+}
+
+/// What the transcriber keeps of each spectrum, so long takes don't hold on to every FFT frame.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameFeatures {
+    pub time: f32,
+    pub flux: f32,
+    pub midi: u32,
+    pub salience: f32,
+}
+
+#[derive(Default)]
+pub struct FeatureExtractor {
+    previous: Vec<f32>,
+}
+
+impl FeatureExtractor {
+    pub fn process(&mut self, spectrum: &MagnitudeSpectrum) -> FrameFeatures {
+        let half = spectrum.data.len() / 2;
+        let current = spectrum.data[..half].iter().map(|m| m.ln_1p()).collect::<Vec<_>>();
+
+        // Half-wave rectified spectral flux on the log-compressed magnitudes
+        let flux = if self.previous.len() == current.len() {
+            current.iter().zip(self.previous.iter()).map(|(c, p)| (c - p).max(0.0)).sum::<f32>() / half as f32
+        } else {
+            0.0
+        };
+        self.previous = current;
+
+        let (midi, salience) = estimate_pitch(spectrum);
+
+        FrameFeatures {
+            // The window is centered half a window after `progress`
+            time: spectrum.progress.as_secs_f32() + spectrum.centre_offset(),
+            flux,
+            midi,
+            salience,
+        }
+    }
+}
+
+/// Finds the most salient guitar note by weighted harmonic summation, with its salience in dB above the noise floor.
+pub fn estimate_pitch(spectrum: &MagnitudeSpectrum) -> (u32, f32) {
+    (LOWEST_MIDI..=HIGHEST_MIDI)
+        .map(|midi| {
+            let pitch = midi_to_pitch(midi);
+            let (energy, noise) = (1..=HARMONICS)
+                .map(|h| {
+                    let weight = HARMONIC_DECAY.powi(h as i32 - 1);
+                    (weight * spectrum.pitch_energy(h as f32 * pitch), weight * spectrum.noise_at(h as f32 * pitch))
+                })
+                .fold((0.0, 0.0), |(e, n), (energy, noise)| (e + energy, n + noise));
+            (midi, spectrum.snr_db(energy, noise))
+        })
+        .reduce(|a, b| if a.1 >= b.1 { a } else { b })
+        .unwrap()
+}
+
+/// Picks frames whose flux is a local maximum above an adaptive threshold.
+pub fn pick_onsets(features: &[FrameFeatures]) -> Vec<usize> {
+    let mut onsets: Vec<usize> = Vec::new();
+
+    for i in 1..features.len().saturating_sub(1) {
+        let flux = features[i].flux;
+        if flux < features[i - 1].flux || flux < features[i + 1].flux {
+            continue;
+        }
+
+        let neighbourhood = &features[i.saturating_sub(ONSET_WINDOW)..(i + ONSET_WINDOW).min(features.len())];
+        let mean = neighbourhood.iter().map(|f| f.flux).sum::<f32>() / neighbourhood.len() as f32;
+        if flux < mean + ONSET_DELTA {
+            continue;
+        }
+
+        if let Some(&last) = onsets.last() {
+            if features[i].time - features[last].time < MIN_ONSET_GAP {
+                continue;
+            }
+        }
+        onsets.push(i);
+    }
+
+    onsets
+}
+
+/// Snaps a time in seconds to the nearest `1/grid` of a beat.
+#[inline]
+pub fn quantize(time: f32, bpm: f32, grid: u32) -> f32 {
+    (time * bpm / 60.0 * grid as f32).round() / grid as f32
+}
+
+/// Estimates the tempo and the time of the first beat from the onset strength envelope,
+/// by autocorrelation weighted towards [`PREFERRED_BPM`] to settle octave ambiguity.
+pub fn estimate_tempo(features: &[FrameFeatures], frame_rate: f32) -> (f32, f32) {
+    let mean = features.iter().map(|f| f.flux).sum::<f32>() / features.len().max(1) as f32;
+    let envelope = features.iter().map(|f| f.flux - mean).collect::<Vec<_>>();
+
+    let autocorrelation = |lag: usize| envelope.iter().zip(envelope.iter().skip(lag)).map(|(a, b)| a * b).sum::<f32>();
+    let min_lag = (frame_rate * 60.0 / MAX_BPM).floor().max(1.0) as usize;
+    let max_lag = (frame_rate * 60.0 / MIN_BPM).ceil() as usize;
+
+    let weighted = (min_lag - 1..=max_lag + 1).map(|lag| {
+        let bpm = frame_rate * 60.0 / lag as f32;
+        let prior = (-0.5 * (bpm / PREFERRED_BPM).log2().powi(2)).exp();
+        autocorrelation(lag) * prior
+    }).collect::<Vec<_>>();
+
+    let best = (1..weighted.len() - 1)
+        .reduce(|a, b| if weighted[a] >= weighted[b] { a } else { b })
+        .unwrap_or(1);
+
+    // Parabolic interpolation around the peak, since a whole number of frames is too coarse a period
+    let (left, centre, right) = (weighted[best - 1], weighted[best], weighted[best + 1]);
+    let denominator = left - 2.0 * centre + right;
+    let shift = if denominator.abs() > f32::EPSILON { 0.5 * (left - right) / denominator } else { 0.0 };
+    let period = (best + min_lag - 1) as f32 + shift.clamp(-0.5, 0.5);
+
+    let phase = (0..period.ceil() as usize).map(|phase| {
+        let strength = (0..)
+            .map(|k| (phase as f32 + k as f32 * period).round() as usize)
+            .take_while(|i| *i < envelope.len())
+            .map(|i| envelope[i])
+            .sum::<f32>();
+        (phase, strength)
+    })
+    .reduce(|a, b| if a.1 >= b.1 { a } else { b })
+    .map_or(0, |(phase, _)| phase);
+
+    let first_beat = features.get(phase).map_or(0.0, |f| f.time);
+    (frame_rate * 60.0 / period, first_beat)
+}
+
+/// Turns onsets into `(beat, midi)` pairs, taking the pitch from the strongest frame just after each onset.
+/// The grid is laid from `offset` seconds, which keeps its original time rather than landing on a beat.
+pub fn transcribe(features: &[FrameFeatures], bpm: f32, offset: f32, grid: u32, threshold: f32) -> Vec<(f32, u32)> {
+    let mut notes: Vec<(f32, u32)> = Vec::new();
+
+    for onset in pick_onsets(features) {
+        let Some(frame) = features[onset..(onset + 3).min(features.len())]
+            .iter()
+            .reduce(|a, b| if a.salience >= b.salience { a } else { b })
+        else {
+            continue
+        };
+
+        if frame.salience < threshold {
+            continue;
+        }
+
+        let beat = quantize(features[onset].time - offset, bpm, grid) + offset * bpm / 60.0;
+        if notes.last().is_some_and(|(b, _)| *b == beat) {
+            continue;
+        }
+        notes.push((beat, frame.midi));
+    }
+
+    notes
+}
+
+/// Assigns each note a string and fret, minimizing hand movement along the line.
+/// Open strings don't move the hand, and lower positions are slightly preferred.
+pub fn assign_fingering(notes: &[(f32, u32)]) -> Vec<Note> {
+    let candidates = |midi: u32| Tab::ALL.iter()
+        .filter(|tab| tab.midi() <= midi && midi - tab.midi() <= MAX_FRET)
+        .map(|tab| (*tab, midi - tab.midi()))
+        .collect::<Vec<_>>();
+
+    let position_cost = |fret: u32| fret as f32 * 0.1;
+    let movement_cost = |from: u32, to: u32| if from == 0 || to == 0 { 0.0 } else { from.abs_diff(to) as f32 };
+
+    // Viterbi over (string, fret) candidates, keeping back-pointers to recover the path
+    let mut layers: Vec<Vec<(Tab, u32, f32, usize)>> = Vec::new();
+    for (_, midi) in notes.iter() {
+        let options = candidates(*midi);
+        if options.is_empty() {
+            continue;
+        }
+
+        let layer = options.into_iter().map(|(tab, fret)| {
+            let (cost, back) = match layers.last() {
+                Some(previous) => previous.iter().enumerate()
+                    .map(|(i, (_, prev_fret, prev_cost, _))| (prev_cost + movement_cost(*prev_fret, fret), i))
+                    .reduce(|a, b| if a.0 <= b.0 { a } else { b })
+                    .unwrap(),
+                None => (0.0, 0),
+            };
+            (tab, fret, cost + position_cost(fret), back)
+        }).collect();
+        layers.push(layer);
+    }
+
+    let Some(last) = layers.last() else { return Vec::new() };
+    let mut index = last.iter().enumerate()
+        .reduce(|a, b| if a.1.2 <= b.1.2 { a } else { b })
+        .unwrap().0;
+
+    let mut fingering = Vec::with_capacity(layers.len());
+    for layer in layers.iter().rev() {
+        let (tab, fret, _, back) = layer[index];
+        fingering.push((tab, fret));
+        index = back;
+    }
+    fingering.reverse();
+
+    notes.iter()
+        .filter(|(_, midi)| !candidates(*midi).is_empty())
+        .zip(fingering)
+        .map(|((beat, _), (tab, fret))| Note { tab, fret, beat: *beat })
+        .collect()
+}
+
+#[derive(Resource)]
+pub struct Recorder {
+    pub bpm: f32,
+    pub grid: u32,
+    recording: bool,
+    stopwatch: Stopwatch,
+    extractor: FeatureExtractor,
+    features: Vec<FrameFeatures>,
+    notes: Vec<Note>,
+    name: String,
+    /// Whether the player has been warned that saving replaces the chart already under `name`.
+    overwrite: bool,
+    status: String,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Recorder {
+            bpm: 80.0,
+            grid: 2,
+            recording: false,
+            stopwatch: Stopwatch::new(),
+            extractor: FeatureExtractor::default(),
+            features: Vec::new(),
+            notes: Vec::new(),
+            name: "recorded".to_owned(),
+            overwrite: false,
+            status: String::new(),
+        }
+    }
+}
+
+impl Recorder {
+    /// Where the chart is saved under the assets, as long as its name can't reach outside `songs/`.
+    fn path(&self) -> Result<String, ChartEditorError> {
+        let name = self.name.trim();
+        if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+            return Err(ChartEditorError::InvalidName(name.to_owned()));
+        }
+        Ok(format!("songs/{}.song", name))
+    }
+
+    fn start(&mut self, mic: &Mic) {
+        if let Some(sender) = &mic.mir_sender {
+            let _ = sender.send(MIRIntruction::SongStart);
+        }
+        self.recording = true;
+        self.stopwatch.reset();
+        self.extractor = FeatureExtractor::default();
+        self.features.clear();
+        self.notes.clear();
+        self.overwrite = false;
+        self.status.clear();
+    }
+
+    fn stop(&mut self) {
+        self.recording = false;
+
+        // Anything played during the count-in bar is dropped
+        let count_in = RECORDING_BEATS_PER_BAR as f32 * 60.0 / self.bpm;
+        let played = self.features.iter().copied().filter(|f| f.time >= count_in).collect::<Vec<_>>();
+
+        let transcribed = transcribe(&played, self.bpm, 0.0, self.grid, SCORE_THRESHOLD);
+        self.notes = assign_fingering(&transcribed);
+        self.status = format!("Transcribed {} notes", self.notes.len());
+    }
+
+    /// Saves the chart, returning its path. An existing chart is only replaced once the player has been warned,
+    /// or it's the one this recording was last saved as.
+    fn save(&mut self) -> Result<String, ChartEditorError> {
+        let path = self.path()?;
+        let file = format!("{}/{}", ASSET_DIR, path);
+        if !self.overwrite && std::path::Path::new(&file).exists() {
+            return Err(ChartEditorError::AlreadyExists(path));
+        }
+
+        let song = SongData {
+            backing: None,
+            bpm: self.bpm,
+            beats_per_bar: RECORDING_BEATS_PER_BAR,
+            notes: self.notes.clone(),
+        };
+        let text = song.to_ron()?;
+        std::fs::write(file, text)?;
+        self.overwrite = true;
+        Ok(path)
+    }
+
+    /// Reports a failed save, arming the next one to overwrite if that's what stopped it.
+    fn save_failed(&mut self, error: ChartEditorError) {
+        self.status = match error {
+            ChartEditorError::AlreadyExists(path) => {
+                self.overwrite = true;
+                format!("{} already exists, save again to replace it", path)
+            },
+            error => format!("Failed to save: {}", error),
+        };
+    }
+}
+
+fn recorder_clock(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut spectra: EventReader<MagnitudeSpectrum>,
+    clicks: Res<MetronomeClicks>,
+    mut recorder: ResMut<Recorder>,
+) {
+    if !recorder.recording {
+        spectra.clear();
+        return;
+    }
+
+    let bps = recorder.bpm / 60.0;
+    let prev_beat = recorder.stopwatch.elapsed_secs() * bps;
+    recorder.stopwatch.tick(time.delta());
+    let this_beat = recorder.stopwatch.elapsed_secs() * bps;
+
+    if prev_beat == 0.0 || prev_beat.floor() < this_beat.floor() {
+        let accent = this_beat.floor() as u32 % RECORDING_BEATS_PER_BAR == 0;
+        clicks.spawn(&mut commands, accent, METRONOME_VOLUME);
+    }
+
+    for spectrum in spectra.read() {
+        let features = recorder.extractor.process(spectrum);
+        recorder.features.push(features);
+    }
+}
+
+fn recorder_ui(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut recorder: ResMut<Recorder>,
+    asset_server: Res<AssetServer>,
+    mic: Res<Mic>,
+) {
+    let ctx = contexts.ctx_mut();
+
+    egui::CentralPanel::default().show(ctx, |ui| {
+        ui.heading("Record Chart");
+        ui.separator();
+
+        ui.add_enabled_ui(!recorder.recording, |ui| {
+            ui.add(egui::Slider::new(&mut recorder.bpm, 40.0..=200.0).text("BPM"));
+            let grid = recorder.grid;
+            egui::ComboBox::from_label("Quantize to")
+                .selected_text(format!("1/{} beat", grid))
+                .show_ui(ui, |ui| {
+                    for division in GRID_DIVISIONS {
+                        ui.selectable_value(&mut recorder.grid, division, format!("1/{} beat", division));
+                    }
+                });
+        });
+
+        ui.separator();
+
+        if recorder.recording {
+            let beat = recorder.stopwatch.elapsed_secs() * recorder.bpm / 60.0;
+            let bar = beat as u32 / RECORDING_BEATS_PER_BAR;
+            if bar == 0 {
+                ui.label(format!("Count-in: {}", beat as u32 % RECORDING_BEATS_PER_BAR + 1));
+            } else {
+                ui.label(format!("Recording bar {}, beat {}", bar, beat as u32 % RECORDING_BEATS_PER_BAR + 1));
+            }
+            if ui.button("Stop").clicked() {
+                recorder.stop();
+            }
+        }
+        else if ui.add_enabled(mic.mir_sender.is_some(), egui::Button::new("Record")).clicked() {
+            recorder.start(&mic);
+        }
+
+        ui.separator();
+
+        if !recorder.notes.is_empty() && !recorder.recording {
+            egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                for note in recorder.notes.iter() {
+                    ui.label(format!("beat {:.2}: {:?} fret {}", note.beat, note.tab, note.fret));
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Name:");
+                if ui.text_edit_singleline(&mut recorder.name).changed() {
+                    recorder.overwrite = false;
+                }
+            });
+
+            ui.horizontal(|ui| {
+                if ui.button("Save").clicked() {
+                    match recorder.save() {
+                        Ok(path) => recorder.status = format!("Saved {}", path),
+                        Err(e) => recorder.save_failed(e),
+                    }
+                }
+                if ui.button("Open in Editor").clicked() {
+                    match recorder.save() {
+                        Ok(path) => match ChartEditor::open(&path, &asset_server) {
+                            Ok(editor) => {
+                                commands.insert_resource(editor);
+                                next_state.set(GameState::Editor);
+                            },
+                            Err(e) => recorder.status = format!("Failed to open chart: {}", e),
+                        },
+                        Err(e) => recorder.save_failed(e),
+                    }
+                }
+            });
+        }
+
+        if !recorder.status.is_empty() {
+            ui.colored_label(Color32::LIGHT_BLUE, &recorder.status);
+        }
+
+        ui.separator();
+        if ui.add_enabled(!recorder.recording, egui::Button::new("Main Menu")).clicked() {
+            next_state.set(GameState::Settings);
+        }
+    });
+}
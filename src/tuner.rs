@@ -0,0 +1,209 @@
+use bevy::prelude::*;
+use bevy_egui::{egui::{self, Color32, Pos2, Sense, Stroke}, EguiContexts};
+
+use crate::{game::SCORE_THRESHOLD, mic::MagnitudeSpectrum, songs::{midi_to_name, midi_to_pitch, pitch_to_midi, Tab}, transcribe::estimate_pitch, GameState};
+
+pub const TUNER_HARMONICS: usize = 4;
+pub const IN_TUNE_CENTS: f32 = 5.0;
+pub const NEEDLE_RANGE_CENTS: f32 = 50.0;
+pub const NEEDLE_SMOOTHING: f32 = 0.3;
+
+pub const STANDARD_REFERENCE: f32 = 440.0;
+pub const OFFSET_ESTIMATION_NOTES: usize = 8;
+pub const MIN_OFFSET_ESTIMATES: usize = 3;
+
+pub struct TunerPlugin;
+
+impl Plugin for TunerPlugin {
+    fn build(&self, app: &mut App) {
+        app .init_resource::<Tuner>()
+            .init_resource::<Tuning>()
+            .add_systems(Update, (update_tuner, tuner_window).chain().run_if(in_state(GameState::Settings).or_else(in_state(GameState::Paused))));
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TunerReading {
+    pub pitch: f32,
+    pub midi: u32,
+    pub cents: f32,
+    pub string: Tab,
+    pub string_cents: f32,
+}
+
+#[derive(Resource, Default)]
+pub struct Tuner {
+    pub open: bool,
+    pub reading: Option<TunerReading>,
+}
+
+/// Where the player's instrument sits, which every expected pitch is adjusted by.
+#[derive(Resource)]
+pub struct Tuning {
+    /// Frequency of A4 that charts are played against.
+    pub reference: f32,
+    /// How far the instrument is from the reference, in cents.
+    pub offset_cents: f32,
+    /// Re-estimates the offset from the first notes of each song.
+    pub auto_detect: bool,
+    estimates: Vec<f32>,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            reference: STANDARD_REFERENCE,
+            offset_cents: 0.0,
+            auto_detect: true,
+            estimates: Vec::new(),
+        }
+    }
+}
+
+impl Tuning {
+    /// Moves a pitch from A4 = 440 Hz to the configured reference.
+    pub fn reference_pitch(&self, pitch: f32) -> f32 {
+        pitch * self.reference / STANDARD_REFERENCE
+    }
+
+    /// The pitch the player's instrument actually produces for a note.
+    pub fn adjust(&self, pitch: f32) -> f32 {
+        self.reference_pitch(pitch) * 2.0_f32.powf(self.offset_cents / 1200.0)
+    }
+
+    pub fn start_estimate(&mut self) {
+        self.estimates.clear();
+    }
+
+    pub fn is_estimating(&self) -> bool {
+        self.auto_detect && self.estimates.len() < OFFSET_ESTIMATION_NOTES
+    }
+
+    /// Adds how far one played note was from the reference. The offset follows the median,
+    /// so a single wrong note doesn't drag it away.
+    pub fn add_estimate(&mut self, cents: f32) {
+        if !self.is_estimating() {
+            return;
+        }
+        self.estimates.push(cents);
+
+        if self.estimates.len() >= MIN_OFFSET_ESTIMATES {
+            let mut sorted = self.estimates.clone();
+            sorted.sort_by(f32::total_cmp);
+            self.offset_cents = sorted[sorted.len() / 2];
+        }
+    }
+}
+
+/// Interpolates the true peak frequency near `pitch` by fitting a parabola to the log magnitudes
+/// of the strongest bin and its neighbours.
+pub fn interpolate_peak(spectrum: &MagnitudeSpectrum, pitch: f32) -> Option<(f32, f32)> {
+    let bin_width = spectrum.bin_width();
+    let search = (pitch * 2.0_f32.powf(-0.5 / 12.0) / bin_width).floor() as usize..=(pitch * 2.0_f32.powf(0.5 / 12.0) / bin_width).ceil() as usize;
+
+    let peak = search
+        .filter(|i| 0 < *i && *i + 1 < spectrum.data.len() / 2)
+        .reduce(|a, b| if spectrum.data[a] >= spectrum.data[b] { a } else { b })?;
+
+    let log_magnitude = |i: usize| spectrum.data[i].max(f32::MIN_POSITIVE).ln();
+    let (left, centre, right) = (log_magnitude(peak - 1), log_magnitude(peak), log_magnitude(peak + 1));
+    let denominator = left - 2.0 * centre + right;
+    let shift = if denominator.abs() > f32::EPSILON { 0.5 * (left - right) / denominator } else { 0.0 };
+
+    Some(((peak as f32 + shift.clamp(-0.5, 0.5)) * bin_width, spectrum.data[peak]))
+}
+
+/// Estimates the fundamental with sub-bin precision, by averaging the interpolated harmonic peaks.
+/// Higher harmonics are weighted up, since each one divides the bin error by its number.
+pub fn estimate_fundamental(spectrum: &MagnitudeSpectrum) -> Option<f32> {
+    let (midi, salience) = estimate_pitch(spectrum);
+    if salience < SCORE_THRESHOLD {
+        return None;
+    }
+    let coarse = midi_to_pitch(midi);
+
+    let (sum, weight) = (1..=TUNER_HARMONICS)
+        .filter_map(|h| interpolate_peak(spectrum, coarse * h as f32).map(|(peak, magnitude)| (peak / h as f32, magnitude * h as f32)))
+        .fold((0.0, 0.0), |(sum, weight), (f0, w)| (sum + f0 * w, weight + w));
+
+    (weight > 0.0).then(|| sum / weight)
+}
+
+impl TunerReading {
+    pub fn new(pitch: f32, tuning: &Tuning) -> Self {
+        let fractional_midi = pitch_to_midi(pitch * STANDARD_REFERENCE / tuning.reference);
+        let midi = fractional_midi.round().max(0.0) as u32;
+
+        let string_cents = |tab: &Tab| 1200.0 * (pitch / tuning.reference_pitch(tab.pitch())).log2();
+        let string = Tab::ALL.iter()
+            .copied()
+            .reduce(|a, b| if string_cents(&a).abs() <= string_cents(&b).abs() { a } else { b })
+            .unwrap();
+
+        TunerReading {
+            pitch,
+            midi,
+            cents: 100.0 * (fractional_midi - midi as f32),
+            string,
+            string_cents: string_cents(&string),
+        }
+    }
+}
+
+fn update_tuner(mut tuner: ResMut<Tuner>, tuning: Res<Tuning>, mut spectra: EventReader<MagnitudeSpectrum>) {
+    if !tuner.open {
+        spectra.clear();
+        return;
+    }
+
+    for spectrum in spectra.read() {
+        let Some(pitch) = estimate_fundamental(spectrum) else { continue };
+
+        // Smooth only while the same note rings, so the needle doesn't lag behind a new string
+        let pitch = match tuner.reading {
+            Some(previous) if (pitch_to_midi(pitch) - pitch_to_midi(previous.pitch)).abs() < 0.5 => {
+                previous.pitch + NEEDLE_SMOOTHING * (pitch - previous.pitch)
+            },
+            _ => pitch,
+        };
+        tuner.reading = Some(TunerReading::new(pitch, &tuning));
+    }
+}
+
+fn tuner_window(mut contexts: EguiContexts, mut tuner: ResMut<Tuner>, mut tuning: ResMut<Tuning>) {
+    let mut open = tuner.open;
+
+    egui::Window::new("Tuner").open(&mut open).resizable(false).show(contexts.ctx_mut(), |ui| {
+        let Some(reading) = tuner.reading else {
+            ui.label("Play a string");
+            return;
+        };
+
+        let color = if reading.string_cents.abs() <= IN_TUNE_CENTS { Color32::GREEN } else { Color32::YELLOW };
+
+        ui.heading(egui::RichText::new(format!("{:?} string", reading.string)).color(color));
+        ui.label(format!("{:+.0} cents", reading.string_cents));
+        ui.label(format!("Hearing {} at {:.1} Hz ({:+.0} cents)", midi_to_name(reading.midi), reading.pitch, reading.cents));
+
+        let (response, painter) = ui.allocate_painter(egui::Vec2::new(240.0, 130.0), Sense::hover());
+        let pivot = Pos2::new(response.rect.center().x, response.rect.bottom() - 10.0);
+        let radius = 110.0;
+        let angle_of = |cents: f32| (cents / NEEDLE_RANGE_CENTS).clamp(-1.0, 1.0) * std::f32::consts::FRAC_PI_3;
+        let point_at = |angle: f32, r: f32| pivot + egui::Vec2::new(angle.sin(), -angle.cos()) * r;
+
+        for tick in (-50..=50).step_by(10) {
+            let angle = angle_of(tick as f32);
+            let inner = if tick == 0 { radius - 20.0 } else { radius - 10.0 };
+            painter.line_segment([point_at(angle, inner), point_at(angle, radius)], Stroke::new(if tick == 0 { 2.0 } else { 1.0 }, Color32::GRAY));
+        }
+        painter.line_segment([pivot, point_at(angle_of(reading.string_cents), radius - 5.0)], Stroke::new(3.0, color));
+        painter.circle_filled(pivot, 4.0, color);
+
+        ui.label(format!("A4 = {:.0} Hz, offset {:+.0} cents", tuning.reference, tuning.offset_cents));
+        if ui.button("Calibrate from this string").on_hover_text("Score notes against the guitar as it is tuned now").clicked() {
+            tuning.offset_cents = reading.string_cents;
+        }
+    });
+
+    tuner.open = open;
+}
@@ -0,0 +1,68 @@
+use mir_project::{
+    game::{tab_to_column, COLUMN_SPACE},
+    mic::MagnitudeSpectrum,
+    songs::{Note, Tab},
+    WIDTH,
+};
+
+fn note(tab: Tab, fret: u32) -> Note {
+    Note { tab, fret, beat: 0.0 }
+}
+
+#[test]
+fn strings_get_evenly_spaced_columns_from_low_to_high() {
+    let columns = Tab::ALL.map(tab_to_column);
+
+    for pair in columns.windows(2) {
+        assert!((pair[1] - pair[0] - COLUMN_SPACE).abs() < 1e-3);
+    }
+    // The highway is centred, with a column's space either side of the outer strings
+    assert!((columns[0] + columns[5]).abs() < 1e-3);
+    assert!(columns.iter().all(|x| x.abs() < WIDTH / 2.0));
+}
+
+#[test]
+fn open_strings_are_at_their_tuning() {
+    for tab in Tab::ALL {
+        assert_eq!(note(tab, 0).pitch(), tab.pitch());
+    }
+}
+
+#[test]
+fn frets_are_semitones() {
+    assert!((note(Tab::A2, 12).pitch() - 220.0).abs() < 0.01);
+    assert!((note(Tab::E4, 5).pitch() - 440.0).abs() < 0.1);
+    // The fifth fret is the next string up, except on the G string
+    assert!((note(Tab::E2, 5).pitch() - Tab::A2.pitch()).abs() < 0.1);
+    assert!((note(Tab::G3, 4).pitch() - Tab::B3.pitch()).abs() < 0.1);
+    assert_eq!(note(Tab::D3, 7).midi(), Tab::D3.midi() + 7);
+}
+
+/// A spectrum with one bin per 100 Hz, whose magnitudes are the bin numbers.
+fn ramp() -> MagnitudeSpectrum {
+    MagnitudeSpectrum {
+        data: vec![0.0, 1.0, 2.0, 3.0],
+        srate: 400.0,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn amplitude_interpolates_between_bins() {
+    let spectrum = ramp();
+
+    assert_eq!(spectrum.amplitude_at(100.0), 1.0);
+    assert!((spectrum.amplitude_at(150.0) - 1.5).abs() < 1e-6);
+    assert!((spectrum.amplitude_at(225.0) - 2.25).abs() < 1e-6);
+}
+
+#[test]
+fn amplitude_wraps_around_past_the_last_bin() {
+    let spectrum = ramp();
+
+    // Between the last bin and the first
+    assert!((spectrum.amplitude_at(350.0) - 1.5).abs() < 1e-6);
+    // A whole spectrum up lands back where it started
+    assert!((spectrum.amplitude_at(500.0) - spectrum.amplitude_at(100.0)).abs() < 1e-6);
+    assert!((spectrum.amplitude_at(650.0) - spectrum.amplitude_at(250.0)).abs() < 1e-6);
+}
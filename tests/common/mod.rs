@@ -0,0 +1,291 @@
+//! Runs the game headless against a fake mic, which plays a synthesized guitar into the real analysis thread.
+// Each test crate uses its own part of this
+#![allow(dead_code)]
+
+use std::{path::PathBuf, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+
+use bevy::{
+    audio::{AudioPlugin, Decodable},
+    gizmos::GizmoPlugin,
+    input::{keyboard::{Key, KeyboardInput}, ButtonState, InputPlugin},
+    prelude::*,
+    render::render_resource::Shader,
+    tasks::block_on,
+    time::TimeUpdateStrategy,
+    window::{ExitCondition, PrimaryWindow},
+};
+use bevy_egui::EguiPlugin;
+use crossbeam_channel::{bounded, Sender};
+use mir_project::{
+    audio::{AudioSourcesPlugin, Pluck, PLUCK_SAMPLE_RATE},
+    calibration::CalibrationPlugin,
+    game::{CurrentSong, GamePlugin, NoteResult, PlaySong},
+    mic::{spawn_analysis, MIRIntruction, Mic, MicPlugin, SampleChunk, SAMPLE_CHUNK_SIZE},
+    replay::SessionList,
+    session::SessionRecorder,
+    settings::SettingsUiPlugin,
+    songs::{Note, SongData, SongPlugin, Tab},
+    tuner::{Tuner, Tuning},
+    GameState,
+};
+
+/// The fake mic records at the rate plucks are synthesized at, so they go in untouched.
+pub const SAMPLE_RATE: u32 = PLUCK_SAMPLE_RATE;
+/// Time each update advances the game by, at 60 fps.
+pub const FRAME: Duration = Duration::from_micros(16_667);
+/// Updates a song gets to finish in before a test gives up on it.
+pub const MAX_FRAMES: usize = 60 * 120;
+pub const PLUCK_LENGTH: Duration = Duration::from_millis(1200);
+
+static CHARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// How the fake guitarist plays a chart.
+#[derive(Clone, Debug)]
+pub struct Performance {
+    /// Seconds every note is played after it's due, or before if negative.
+    pub timing_error: f32,
+    pub detune_cents: f32,
+    /// Amplitude of the white noise under everything, from the count-in on.
+    pub noise: f32,
+    /// Indices of notes in the chart that aren't played at all.
+    pub skipped: Vec<usize>,
+    /// Seconds into the song to pause at with Escape, and how many seconds to stay paused for.
+    pub pause: Option<(f32, f32)>,
+    /// What the song is slowed down to, which the playing follows.
+    pub speed: f32,
+}
+
+impl Default for Performance {
+    fn default() -> Self {
+        Performance {
+            timing_error: 0.0,
+            detune_cents: 0.0,
+            noise: 0.001,
+            skipped: Vec::new(),
+            pause: None,
+            speed: 1.0,
+        }
+    }
+}
+
+impl Performance {
+    /// The chart played from its first beat, with time to ring out after the last note.
+    pub fn synthesize(&self, chart: &SongData) -> Vec<f32> {
+        let seconds_per_beat = 60.0 / chart.bpm / self.speed;
+        let end = chart.notes.iter().map(|note| note.beat).fold(0.0, f32::max) * seconds_per_beat + 2.0;
+        let mut samples = vec![0.0; (end * SAMPLE_RATE as f32) as usize];
+
+        for (i, note) in chart.notes.iter().enumerate().filter(|(i, _)| !self.skipped.contains(i)) {
+            let start = ((note.beat * seconds_per_beat + self.timing_error).max(0.0) * SAMPLE_RATE as f32) as usize;
+            let frequency = note.pitch() * 2.0_f32.powf(self.detune_cents / 1200.0);
+            for (sample, pluck) in samples.iter_mut().skip(start).zip(Pluck::new(frequency, PLUCK_LENGTH).decoder()) {
+                *sample += pluck;
+            }
+        }
+
+        let mut noise = Noise::default();
+        for sample in samples.iter_mut() {
+            *sample += noise.next(self.noise);
+        }
+        samples
+    }
+}
+
+/// Xorshift white noise, so every run hears the same room.
+pub struct Noise(u32);
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise(0x9e3779b9)
+    }
+}
+
+impl Noise {
+    pub fn next(&mut self, amplitude: f32) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0) * amplitude
+    }
+}
+
+pub fn chart(bpm: f32, notes: &[(Tab, u32, f32)]) -> SongData {
+    SongData {
+        backing: None,
+        bpm,
+        beats_per_bar: 4,
+        notes: notes.iter().map(|(tab, fret, beat)| Note { tab: *tab, fret: *fret, beat: *beat }).collect(),
+    }
+}
+
+/// Where charts for the tests are written, for the asset server to load them from.
+fn charts_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("mir_project_tests_{}", std::process::id()))
+}
+
+pub struct Harness {
+    pub app: App,
+    chunks: Sender<SampleChunk>,
+    instructions: Sender<MIRIntruction>,
+    /// Every state the game has been in, in order, without repeats.
+    pub states: Vec<GameState>,
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        std::fs::create_dir_all(charts_dir()).unwrap();
+
+        let mut app = App::new();
+        app .add_plugins((
+                MinimalPlugins,
+                AssetPlugin { file_path: charts_dir().to_string_lossy().into_owned(), ..default() },
+                AudioPlugin::default(),
+                InputPlugin,
+                // A window that's never opened, for egui to draw into
+                WindowPlugin { primary_window: Some(Window::default()), exit_condition: ExitCondition::DontExit, close_when_requested: false },
+                EguiPlugin,
+            ))
+            // What the render plugins would otherwise have registered for gizmos and egui
+            .init_asset::<Shader>()
+            .init_asset::<Image>()
+            .add_plugins(GizmoPlugin)
+            .add_plugins((AudioSourcesPlugin, MicPlugin, SongPlugin, SettingsUiPlugin, GamePlugin, CalibrationPlugin))
+            .init_resource::<SessionList>()
+            .init_resource::<Tuning>()
+            .init_resource::<Tuner>()
+            .init_resource::<SessionRecorder>()
+            .insert_resource(TimeUpdateStrategy::ManualDuration(FRAME))
+            .init_state::<GameState>();
+        app.update();
+
+        // Stands in for a connected device
+        let input = spawn_analysis(SAMPLE_RATE as f32);
+        let mut mic = app.world.resource_mut::<Mic>();
+        mic.mir_sender = Some(input.instructions.clone());
+        mic.mir_receiver = Some(input.frames);
+        mic.overruns = Some(input.overruns);
+
+        let mut harness = Harness { app, chunks: input.chunks, instructions: input.instructions, states: Vec::new() };
+        harness.record_state();
+        harness
+    }
+
+    pub fn state(&self) -> GameState {
+        self.app.world.resource::<State<GameState>>().get().clone()
+    }
+
+    fn record_state(&mut self) {
+        let state = self.state();
+        if self.states.last() != Some(&state) {
+            self.states.push(state);
+        }
+    }
+
+    /// Feeds samples to the analysis thread, and waits for it to analyse them so it keeps up with the game.
+    fn feed(&self, samples: &[f32]) {
+        for part in samples.chunks(SAMPLE_CHUNK_SIZE) {
+            self.chunks.send(SampleChunk::new(part)).unwrap();
+        }
+        self.sync();
+    }
+
+    /// Waits for the analysis thread to get through everything sent to it so far. Instructions are taken in order,
+    /// so this also waits out a song start throwing away what was queued before it.
+    fn sync(&self) {
+        let (reply, done) = bounded(1);
+        self.instructions.send(MIRIntruction::Sync(reply)).unwrap();
+        done.recv().unwrap();
+    }
+
+    /// Presses or lets go of Escape, as the window would, for the next update to see.
+    fn escape(&mut self, state: ButtonState) {
+        let window = self.app.world.query_filtered::<Entity, With<PrimaryWindow>>().single(&self.app.world);
+        self.app.world.send_event(KeyboardInput { key_code: KeyCode::Escape, logical_key: Key::Escape, state, window });
+    }
+
+    /// Starts `chart` from the settings screen the way the Play button does, plays it through the fake mic,
+    /// and returns how each note was judged once the song is over.
+    pub fn play(&mut self, chart: &SongData, performance: &Performance) -> Vec<NoteResult> {
+        let name = format!("chart{}.song", CHARTS.fetch_add(1, Ordering::Relaxed));
+        std::fs::write(charts_dir().join(&name), chart.to_ron().unwrap()).unwrap();
+
+        // Loaded up front, so the game finds it ready rather than the test waiting on the IO thread
+        let asset_server = self.app.world.resource::<AssetServer>().clone();
+        let _chart = block_on(asset_server.load_untyped_async(name.clone())).unwrap();
+        self.app.world.send_event(PlaySong { path: name, speed: performance.speed });
+
+        let audio = performance.synthesize(chart);
+        let per_frame = (FRAME.as_secs_f32() * SAMPLE_RATE as f32).round() as usize;
+        // Where in the song's audio to pause, and for how many updates
+        let pause = performance.pause.map(|(at, length)| ((at * SAMPLE_RATE as f32) as usize, (length / FRAME.as_secs_f32()).round() as usize));
+        let mut noise = Noise::default();
+        let mut played = None;
+        let mut paused_frames = None;
+        let mut escape_held = false;
+
+        for _ in 0..MAX_FRAMES {
+            self.app.update();
+            self.record_state();
+
+            // Escape is let go the update after it's pressed, so the next press is a fresh one
+            if escape_held {
+                self.escape(ButtonState::Released);
+                escape_held = false;
+            }
+
+            match self.state() {
+                GameState::PostSongInfo => break,
+                // Waiting for the Play request, then for the loaded chart to be picked up
+                GameState::Settings | GameState::SongLoading => {},
+                GameState::SongPlaying => {
+                    let position = match played {
+                        Some(position) => position,
+                        None if self.app.world.resource::<CurrentSong>().mic_started() => {
+                            self.sync();
+                            0
+                        },
+                        None => {
+                            // The count-in is the room alone, which the noise floor learns from
+                            let room: Vec<f32> = (0..per_frame).map(|_| noise.next(performance.noise)).collect();
+                            self.feed(&room);
+                            continue;
+                        },
+                    };
+
+                    let mut samples: Vec<f32> = audio.iter().skip(position).take(per_frame).copied().collect();
+                    samples.resize_with(per_frame, || noise.next(performance.noise));
+                    self.feed(&samples);
+                    played = Some(position + per_frame);
+
+                    if paused_frames.is_none() && pause.is_some_and(|(at, _)| position + per_frame >= at) {
+                        self.escape(ButtonState::Pressed);
+                        escape_held = true;
+                        paused_frames = Some(0);
+                    }
+                },
+                GameState::Paused => {
+                    // The mic keeps hearing the room while the song waits
+                    let room: Vec<f32> = (0..per_frame).map(|_| noise.next(performance.noise)).collect();
+                    self.feed(&room);
+
+                    let frames = paused_frames.get_or_insert(0);
+                    *frames += 1;
+                    if pause.is_some_and(|(_, length)| *frames == length) {
+                        self.escape(ButtonState::Pressed);
+                        escape_held = true;
+                    }
+                },
+                state => panic!("Unexpected state {:?} while playing", state),
+            }
+        }
+
+        assert_eq!(self.state(), GameState::PostSongInfo, "The song never finished");
+        self.app.world.resource::<CurrentSong>().results().to_vec()
+    }
+}
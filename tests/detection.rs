@@ -0,0 +1,42 @@
+mod common;
+
+use common::{chart, Performance, SAMPLE_RATE};
+use mir_project::{
+    calibration::InputProfile,
+    game::{score_note, HIT_FORGIVENESS, SCORE_THRESHOLD},
+    mic::{spectra, AnalysisConfig},
+    songs::{Note, SongData, Tab},
+};
+
+/// The best score `note` gets over its hit window when `chart` is played, the way the detector judges it.
+fn best_score(chart: &SongData, note: &Note) -> f32 {
+    let audio = Performance::default().synthesize(chart);
+    let due = note.beat * 60.0 / chart.bpm;
+    let profile = InputProfile::default();
+
+    spectra(&audio, SAMPLE_RATE as f32, AnalysisConfig::default())
+        .filter(|spectrum| (spectrum.progress.as_secs_f32() - due).abs() <= HIT_FORGIVENESS)
+        .map(|spectrum| score_note(note.pitch(), &spectrum, &profile).score)
+        .fold(0.0, f32::max)
+}
+
+/// A3, played half a second after a lower note that's still ringing.
+const A3: Note = Note { tab: Tab::G3, fret: 2, beat: 1.0 };
+
+#[test]
+fn hits_an_octave_over_a_ringing_note() {
+    let chart = chart(120.0, &[(Tab::A2, 0, 0.0), (A3.tab, A3.fret, A3.beat)]);
+    assert!(best_score(&chart, &A3) > SCORE_THRESHOLD);
+}
+
+#[test]
+fn hits_a_fifth_over_a_ringing_note() {
+    let chart = chart(120.0, &[(Tab::D3, 0, 0.0), (A3.tab, A3.fret, A3.beat)]);
+    assert!(best_score(&chart, &A3) > SCORE_THRESHOLD);
+}
+
+#[test]
+fn a_ringing_note_alone_does_not_hit_its_harmonic() {
+    let chart = chart(120.0, &[(Tab::A2, 0, 0.0)]);
+    assert!(best_score(&chart, &A3) <= SCORE_THRESHOLD);
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use common::{chart, Harness, Performance};
+use mir_project::{
+    game::{note_mic_time, Miss, NoteHitData, NoteResult, HIT_FORGIVENESS, SCORE_THRESHOLD, TIMING_WINDOW},
+    songs::{SongData, Tab},
+    GameState,
+};
+
+/// A note on each of four strings, far enough apart that each has rung out before the next is listened for.
+fn scale() -> SongData {
+    chart(60.0, &[
+        (Tab::A2, 3, 0.0),
+        (Tab::D3, 2, 2.0),
+        (Tab::G3, 0, 4.0),
+        (Tab::B3, 1, 6.0),
+    ])
+}
+
+fn hits(results: &[NoteResult]) -> usize {
+    results.iter().filter(|result| result.hit).count()
+}
+
+#[test]
+fn plays_through_from_settings_to_post_song_info() {
+    let mut harness = Harness::new();
+    let results = harness.play(&scale(), &Performance::default());
+
+    assert_eq!(harness.states, [GameState::Settings, GameState::SongLoading, GameState::SongPlaying, GameState::PostSongInfo]);
+    assert_eq!(results.len(), scale().notes.len(), "Every note should be judged once");
+}
+
+#[test]
+fn pausing_mid_song_picks_up_where_it_left_off() {
+    // Between the second and third notes, with the room still heard while paused
+    let performance = Performance { pause: Some((3.0, 1.5)), ..Performance::default() };
+    let mut harness = Harness::new();
+    let results = harness.play(&scale(), &performance);
+
+    assert_eq!(harness.states, [
+        GameState::Settings,
+        GameState::SongLoading,
+        GameState::SongPlaying,
+        GameState::Paused,
+        GameState::SongPlaying,
+        GameState::PostSongInfo,
+    ]);
+    assert_eq!(hits(&results), scale().notes.len());
+}
+
+#[test]
+fn clean_playing_hits_every_note() {
+    let results = Harness::new().play(&scale(), &Performance::default());
+
+    assert_eq!(hits(&results), scale().notes.len());
+    assert!(results.iter().all(|result| result.miss.is_none()));
+}
+
+#[test]
+fn half_speed_notes_are_due_twice_as_late() {
+    let chart = scale();
+    // Two seconds into the chart is four into the recording at half speed
+    assert_eq!(note_mic_time(&chart.notes[1], chart.bpm, 0.5), 4.0);
+
+    let performance = Performance { speed: 0.5, ..Performance::default() };
+    let results = Harness::new().play(&chart, &performance);
+
+    assert_eq!(hits(&results), chart.notes.len());
+    for (result, note) in results.iter().zip(&chart.notes) {
+        assert_eq!(result.mic_time(chart.bpm, 0.5), note.beat * 2.0);
+    }
+}
+
+#[test]
+fn small_timing_errors_are_forgiven() {
+    let performance = Performance { timing_error: HIT_FORGIVENESS / 2.0, ..Performance::default() };
+    let results = Harness::new().play(&scale(), &performance);
+
+    assert_eq!(hits(&results), scale().notes.len());
+}
+
+#[test]
+fn playing_after_the_window_is_late() {
+    // Past the hit window, but early enough in the timing window to be told apart from silence
+    let performance = Performance { timing_error: HIT_FORGIVENESS + TIMING_WINDOW * 0.9, ..Performance::default() };
+    let results = Harness::new().play(&scale(), &performance);
+
+    assert_eq!(hits(&results), 0);
+    assert!(results.iter().all(|result| result.miss == Some(Miss::Late)), "{:?}", results.iter().map(|result| result.miss).collect::<Vec<_>>());
+}
+
+#[test]
+fn a_repeated_note_still_ringing_is_not_early() {
+    // The first A2 is still ringing through all of the second's early window
+    let repeated = chart(60.0, &[(Tab::A2, 0, 0.0), (Tab::A2, 0, 1.0)]);
+    let performance = Performance { timing_error: HIT_FORGIVENESS + TIMING_WINDOW * 0.9, ..Performance::default() };
+    let results = Harness::new().play(&repeated, &performance);
+
+    assert_eq!(results[0].miss, Some(Miss::Late));
+    assert_ne!(results[1].miss, Some(Miss::Early));
+}
+
+#[test]
+fn only_an_onset_outside_the_window_is_early_or_late() {
+    let hop = 0.05;
+    let frames = |scores: &[f32]| NoteHitData {
+        data: scores.iter().enumerate().map(|(i, score)| (-(HIT_FORGIVENESS + TIMING_WINDOW) + i as f32 * hop, *score)).collect(),
+        ..NoteHitData::default()
+    };
+    let (over, under) = (SCORE_THRESHOLD + 10.0, SCORE_THRESHOLD - 10.0);
+
+    // Already sounding when listening began, and dying away
+    let ringing = frames(&[over, over - 1.0, over - 2.0, under, under, under, under, under, under, under, under, under, under, under, under, under]);
+    assert_eq!(ringing.classify(SCORE_THRESHOLD, 45), Miss::Silent);
+
+    // Played again over its own ringing, before the window
+    let replayed = frames(&[over - 2.0, over - 4.0, over, over, under, under, under, under, under, under, under, under, under, under, under, under]);
+    assert_eq!(replayed.classify(SCORE_THRESHOLD, 45), Miss::Early);
+
+    // Ringing through the early part, then played after the window
+    let late = frames(&[over, under, under, under, under, under, under, under, under, under, under, under, under, under, over, over]);
+    assert_eq!(late.classify(SCORE_THRESHOLD, 45), Miss::Late);
+}
+
+#[test]
+fn slight_detuning_still_hits() {
+    let performance = Performance { detune_cents: 10.0, ..Performance::default() };
+    let results = Harness::new().play(&scale(), &performance);
+
+    assert_eq!(hits(&results), scale().notes.len());
+}
+
+#[test]
+fn a_semitone_off_is_the_wrong_pitch() {
+    let performance = Performance { detune_cents: 100.0, ..Performance::default() };
+    let results = Harness::new().play(&scale(), &performance);
+
+    assert_eq!(hits(&results), 0);
+    for result in &results {
+        assert_eq!(result.miss, Some(Miss::WrongPitch(result.note.midi() + 1)), "{:?}", result.note);
+    }
+}
+
+#[test]
+fn hits_over_background_noise() {
+    let performance = Performance { noise: 0.02, ..Performance::default() };
+    let results = Harness::new().play(&scale(), &performance);
+
+    assert_eq!(hits(&results), scale().notes.len());
+}
+
+#[test]
+fn skipped_notes_are_silent_misses() {
+    let performance = Performance { skipped: vec![1, 3], ..Performance::default() };
+    let results = Harness::new().play(&scale(), &performance);
+
+    assert_eq!(hits(&results), 2);
+    for result in results.iter().filter(|result| !result.hit) {
+        assert!(matches!(result.note.tab, Tab::D3 | Tab::B3), "{:?} should have been hit", result.note);
+        assert_eq!(result.miss, Some(Miss::Silent));
+    }
+}
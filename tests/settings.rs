@@ -0,0 +1,34 @@
+mod common;
+
+use common::Harness;
+use mir_project::{
+    calibration::{DeviceProfiles, FretThreshold, InputProfile},
+    songs::Tab,
+    GameState,
+};
+
+#[test]
+fn threshold_edits_on_the_settings_screen_are_kept_and_saved() {
+    let mut harness = Harness::new();
+
+    // As the thresholds editor leaves them
+    let mut edited = harness.app.world.resource::<InputProfile>().clone();
+    edited.thresholds.insert(Tab::D3, 42.0);
+    edited.fret_thresholds.push(FretThreshold { tab: Tab::E2, from: 5, to: 9, threshold: 33.0 });
+    *harness.app.world.resource_mut::<InputProfile>() = edited.clone();
+
+    for _ in 0..10 {
+        harness.app.update();
+    }
+    assert_eq!(harness.state(), GameState::Settings);
+    assert_eq!(*harness.app.world.resource::<InputProfile>(), edited, "The edit should outlast the settings screen redrawing");
+
+    // Saving to a device profile stores the live profile
+    let path = std::env::temp_dir().join(format!("mir_project_profiles_{}.ron", std::process::id()));
+    let mut profiles = DeviceProfiles::default();
+    profiles.profiles.insert("Test device".to_owned(), harness.app.world.resource::<InputProfile>().clone());
+    profiles.save_to(&path).unwrap();
+
+    assert_eq!(DeviceProfiles::load_from(&path).unwrap().profiles["Test device"], edited);
+    let _ = std::fs::remove_file(path);
+}